@@ -12,52 +12,26 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
-// Example that demonstrates how to create a cycle stream and zip that
-// with an Interval stream.
+// Example that demonstrates how to create a cycle stream and throttle
+// it so that it produces one item every 500 milliseconds.
+//
+// The source stream is not cloneable, so the `cycle` combinator from
+// the library buffers the items on the first pass and replays them on
+// the following passes.
 
-use futures::stream::Stream;
-use futures::StreamExt;
-use std::iter::Cycle;
-use std::marker::Unpin;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use futures::stream::{self, StreamExt};
 use std::time::Duration;
-use tokio::time::interval;
-
-struct IterCycle<I> {
-    iter: Cycle<I>,
-}
-
-fn iter_cycle<I>(i: I) -> IterCycle<I::IntoIter>
-where
-    I: IntoIterator,
-    I::IntoIter: Clone,
-{
-    IterCycle {
-        iter: i.into_iter().cycle(),
-    }
-}
-
-impl<I> Stream for IterCycle<I>
-where
-    I: Iterator + Clone + Unpin,
-{
-    type Item = <I as Iterator>::Item;
-
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Poll::Ready(self.iter.next())
-    }
-}
+use tokio::time::Instant;
+use tokio_examples::{CycleExt, RateLimit, ThrottleExt};
 
 #[tokio::main]
 async fn main() {
-    // iter_cycle return a stream with Error = (), which means that we
-    // need to map the error from the Interval stream to () as well.
-    let mut primes = iter_cycle(vec![2, 3, 5, 7, 11, 13])
-        .take(20)
-        .zip(interval(Duration::from_millis(500)));
+    let primes = stream::iter(vec![2, 3, 5, 7, 11, 13]);
+    let mut primes = CycleExt::cycle(primes)
+        .max_cycles(3)
+        .throttle(RateLimit::FixedInterval(Duration::from_millis(500)));
 
-    while let Some((number, instant)) = primes.next().await {
-        println!("fire; number={}, instant={:?}", number, instant);
+    while let Some(number) = primes.next().await {
+        println!("fire; number={}, instant={:?}", number, Instant::now());
     }
 }
@@ -13,19 +13,72 @@
 // permissions and limitations under the License.
 
 // Example to demonstrate how to generate an infinite stream of
-// fibonacci numbers. Not that this stream will end with an overflow.
+// fibonacci numbers. Since the numbers grow fast, the stream will
+// overflow after a while, so the example accept a mode on the command
+// line that decides what happens on overflow:
+//
+// - `checked` (default) ends the stream on overflow.
+// - `result` ends the stream with an overflow error.
+// - `wrapping` wraps around and continues forever.
+// - `big` uses big integers and continues forever.
+//
+// It is also possible to start the stream at an arbitrary Fibonacci
+// number using `--start N`, which jumps directly to the N-th number
+// without generating all the numbers before it. The numbers are
+// indexed from 0, so `--start 0` starts with 0 in all modes.
+//
+// ```bash
+// $ cargo run --example fib_stream -- result --start 80
+// ```
 
 extern crate tokio_examples;
 
-use futures::StreamExt;
-use tokio_examples::fibonacci;
+use futures::{Stream, StreamExt};
+use num_bigint::BigUint;
+use std::env;
+use std::fmt::Display;
+use tokio_examples::sequences::{
+    checked_fibonacci_from, try_fibonacci_from, wrapping_fibonacci_from,
+};
+
+// Print all numbers in the stream, or the error if there is one.
+async fn print_numbers<S, T, E>(numbers: S)
+where
+    S: Stream<Item = Result<T, E>>,
+    T: Display,
+    E: Display,
+{
+    tokio::pin!(numbers);
+    while let Some(result) = numbers.next().await {
+        match result {
+            Ok(number) => println!("number: {}", number),
+            Err(err) => println!("error: {}", err),
+        }
+    }
+}
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let numbers = fibonacci();
-    tokio::pin!(numbers);
-    while let Some(number) = numbers.next().await {
-        println!("number: {}", number);
+    let mut mode = "checked".to_string();
+    let mut start = 1;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--start" {
+            let value = args.next().ok_or("missing value for --start")?;
+            start = value.parse()?;
+        } else {
+            mode = arg;
+        }
+    }
+
+    match mode.as_str() {
+        "checked" => print_numbers(checked_fibonacci_from::<u64>(start).map(Ok::<_, String>)).await,
+        "result" => print_numbers(try_fibonacci_from::<u64>(start)).await,
+        "wrapping" => {
+            print_numbers(wrapping_fibonacci_from::<u64>(start).map(Ok::<_, String>)).await
+        }
+        "big" => print_numbers(checked_fibonacci_from::<BigUint>(start).map(Ok::<_, String>)).await,
+        _ => return Err(format!("unknown mode '{}'", mode).into()),
     }
     Ok(())
 }
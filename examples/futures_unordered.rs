@@ -12,14 +12,17 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
-// An example creating a bunch of futures and then collect them using
-// `FuturesUnordered`. Add printouts to see that they are spawed in
-// the right order, but complete in a different order.
+// An example creating a bunch of futures and then running them using
+// a `TaskPool`, which runs a limited number of them at the same time
+// using `FuturesUnordered` internally. Add printouts to see that they
+// are spawed in the right order, but complete in a different order.
+//
+// Each task is only allowed to run for a short time, so some of the
+// tasks will not resolve. These are reported at the end.
 
-use futures::executor::block_on_stream;
-use futures::stream::FuturesUnordered;
+use futures::StreamExt;
 use std::time::Duration;
-use tokio;
+use tokio_examples::TaskPool;
 
 struct Item {
     number: u64,
@@ -53,19 +56,26 @@ async fn main() {
         })
         .collect();
 
-    // This is a stream. We cannot directly iterate over it.
-    let tasks: FuturesUnordered<_> = items
-        .into_iter()
-        .map(|mut item| async move {
-            println!("task {} spawned", item.number);
-            item.resolve().await;
-            item
-        })
-        .collect();
+    let tasks = items.into_iter().map(|mut item| async move {
+        println!("task {} spawned", item.number);
+        item.resolve().await;
+        item
+    });
+
+    // This is a stream, so we wait for the results asynchronously
+    // instead of blocking the thread.
+    let mut results = TaskPool::new(4)
+        .timeout(Duration::from_millis(15))
+        .run(tasks);
+    let mut unresolved = Vec::new();
+    while let Some((index, result)) = results.next().await {
+        match result {
+            Ok(item) => item.print_result(),
+            Err(err) => unresolved.push((index, err)),
+        }
+    }
 
-    // Turn the stream into a blocking iterator on the stream and wait
-    // for them to complete.
-    for item in block_on_stream(tasks) {
-        item.print_result();
+    for (index, err) in unresolved {
+        println!("task {} not resolved: {}", index, err);
     }
 }
@@ -20,66 +20,104 @@
 // have a static lifetime.
 //
 // For the example, we create two independent interval streams that
-// will increase and decrease the version of the shared state at
-// different paces.
+// will increase and decrease the counter of the shared state at
+// different paces, and a third task that watches the state and prints
+// every new version of it.
+//
+// The tasks stop when Ctrl-C is pressed, or after the number of
+// seconds given on the command line, and the final state is printed.
+//
+// ```bash
+// $ cargo run --example global_state -- 10
+// ```
 
-use std::sync::{Arc, Mutex};
+use futures::StreamExt;
+use std::env;
+use std::error::Error;
 use std::time::Duration;
-use tokio::stream::StreamExt;
 use tokio::time::interval;
+use tokio_examples::{Shutdown, VersionedCell};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct State {
-    version: i32,
+    counter: i32,
 }
 
 impl State {
     fn new() -> Self {
-        Self { version: 0 }
+        Self { counter: 0 }
     }
 
     fn inc(&mut self) {
-        self.version += 1;
+        self.counter += 1;
     }
 
     fn dec(&mut self) {
-        self.version -= 1;
+        self.counter -= 1;
     }
 }
 
 #[tokio::main]
-async fn main() {
-    let shared_state = Arc::new(Mutex::new(State::new()));
+async fn main() -> Result<(), Box<dyn Error>> {
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_ctrl_c();
+    if let Some(seconds) = env::args().nth(1) {
+        shutdown.trigger_after(Duration::from_secs(seconds.parse()?));
+    }
+
+    let shared_state = VersionedCell::new(State::new());
 
     // Note that we are here first creating a block where we clone the
-    // Arc of the shared state and then pass that into the async
+    // cell with the shared state and then pass that into the async
     // block. If we didn't do that, the shared_state would be borrowed
     // inside the async block and this block can outlive the
     // shared_state *variable* (not the underlying state).
+    //
+    // The tickers stop when the shutdown is triggered, but an update
+    // that is in progress is always completed.
     let handle1 = tokio::spawn({
         let state = shared_state.clone();
+        let mut ticker = interval(Duration::from_millis(5000)).take_until(shutdown.wait());
         async move {
-            let mut ticker = interval(Duration::from_millis(5000));
             while let Some(instant) = ticker.next().await {
-                let mut locked_state = state.lock().unwrap();
-                locked_state.dec();
-                println!("first - instant={:?}, state={:?}", instant, locked_state);
+                let snapshot = state.update(State::dec).await;
+                println!("first - instant={:?}, state={:?}", instant, snapshot);
             }
         }
     });
 
     let handle2 = tokio::spawn({
         let state = shared_state.clone();
+        let mut ticker = interval(Duration::from_millis(500)).take_until(shutdown.wait());
         async move {
-            let mut ticker = interval(Duration::from_millis(500));
             while let Some(instant) = ticker.next().await {
-                let mut locked_state = state.lock().unwrap();
-                locked_state.inc();
-                println!("second - instant={:?}, state={:?}", instant, locked_state);
+                let snapshot = state.update(State::inc).await;
+                println!("second - instant={:?}, state={:?}", instant, snapshot);
+            }
+        }
+    });
+
+    // This task does not poll the state, it just reacts on changes to
+    // it. It does not hold a clone of the cell, so the stream of changes
+    // ends when all clones of the cell are dropped.
+    let handle3 = tokio::spawn({
+        let mut changes = shared_state.watch().await;
+        async move {
+            while let Some(snapshot) = changes.next().await {
+                if snapshot.value.counter % 5 == 0 {
+                    println!("watcher - state={:?}", snapshot);
+                }
             }
         }
     });
 
     println!("{:?}", handle1.await);
     println!("{:?}", handle2.await);
+    println!("final state: {:?}", shared_state.snapshot().await);
+
+    // Dropping the last clone of the cell ends the watcher once it has
+    // processed all the changes.
+    drop(shared_state);
+    println!("{:?}", handle3.await);
+    Ok(())
 }
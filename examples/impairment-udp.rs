@@ -0,0 +1,180 @@
+// Copyright 2020 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Application that simulates a bad network between UDP clients and a
+//! server.
+//!
+//! It works as a proxy that forwards datagrams received on 0.0.0.0:6142
+//! to 127.0.0.1:6150 and the replies back to the clients, but drops,
+//! duplicates, corrupts, delays, and reorders datagrams in both
+//! directions. This makes it possible to test the other UDP examples
+//! over a lossy path on a single host, for example:
+//!
+//! ```bash
+//! bash-1$ cargo run --example receiver-udp -- --mode reliable --bind 127.0.0.1:6150
+//! bash-2$ cargo run --example impairment-udp -- --drop 10% --duplicate 5% --reorder 10% --seed 42
+//! bash-3$ cargo run --example sender-udp -- --mode reliable --count 100 hello
+//! ```
+//!
+//! The probabilities are given using `--drop`, `--duplicate`,
+//! `--corrupt` (flips one bit), and `--reorder`, either as a number
+//! between 0 and 1 or as a percentage. All datagrams are delayed by
+//! `--delay` milliseconds plus a random jitter of up to `--jitter`
+//! milliseconds, and reordered datagrams are held back for another
+//! `--reorder-delay` milliseconds (50 by default), so datagrams sent
+//! after them overtake them.
+//!
+//! The decisions are random, but the seed used is printed when
+//! starting and can be given using `--seed`, so that a run with a
+//! single client can be repeated with the same impairments.
+//!
+//! Each client gets a session with its own socket, which is closed
+//! after `--session-timeout` seconds without traffic. Statistics for
+//! each direction are printed every ten seconds and when Ctrl-C is
+//! pressed.
+
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::interval;
+use tokio_examples::config::Config;
+use tokio_examples::datagram::DatagramBuffer;
+use tokio_examples::impairment::{Impairer, Impairment, Probability};
+use tokio_examples::random::Random;
+use tokio_examples::session::Sessions;
+use tokio_examples::Shutdown;
+
+// Interval between printing the statistics.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Interval between checking for idle sessions.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+fn make_impairment(config: &Config) -> Result<Impairment, Box<dyn Error>> {
+    let millis = |name| -> Result<Duration, Box<dyn Error>> {
+        Ok(Duration::from_millis(config.parse(name)?.unwrap_or(0)))
+    };
+    let probability = |name| -> Result<Probability, Box<dyn Error>> {
+        Ok(config.parse(name)?.unwrap_or_default())
+    };
+    Ok(Impairment::new()
+        .drop(probability("drop")?)
+        .duplicate(probability("duplicate")?)
+        .corrupt(probability("corrupt")?)
+        .reorder(probability("reorder")?)
+        .delay(millis("delay")?)
+        .jitter(millis("jitter")?)
+        .reorder_delay(millis("reorder-delay")?))
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let server = match config.destinations.as_slice() {
+        [server] => *server,
+        _ => return Err("exactly one destination is needed".into()),
+    };
+    let impairment = make_impairment(&config)?;
+    let seed = match config.parse("seed")? {
+        Some(seed) => seed,
+        None => Random::from_time().next_u64(),
+    };
+    let timeout = Duration::from_secs(config.parse("session-timeout")?.unwrap_or(60));
+    let mut seeds = Random::new(seed);
+    let outbound = Arc::new(Mutex::new(Impairer::new(
+        impairment.clone(),
+        seeds.next_u64(),
+    )));
+    let inbound = Arc::new(Mutex::new(Impairer::new(
+        impairment.clone(),
+        seeds.next_u64(),
+    )));
+
+    let (mut socket, socket_send) = UdpSocket::bind(config.bind).await?.split();
+    let mut buf = DatagramBuffer::new(config.buffer_size)?;
+    let mut sessions = Sessions::new(socket_send, buf.clone())
+        .timeout(timeout)
+        .request_scheduler(outbound.clone())
+        .reply_scheduler(inbound.clone());
+    println!("Listening on: {}", config.bind);
+    println!("Forwarding to: {}", server);
+    println!("Impairment: {}", impairment);
+    println!("Seed: {}", seed);
+
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_ctrl_c();
+    let mut ticks = interval(STATS_INTERVAL);
+    let mut sweeps = interval(SWEEP_INTERVAL);
+    ticks.tick().await;
+    loop {
+        tokio::select! {
+            result = socket.recv_from(buf.recv_buf()) => {
+                // A refused reply to one client should not stop the
+                // proxy for the others.
+                let (bytes, client) = match result {
+                    Ok(received) => received,
+                    Err(err) => {
+                        println!("Receive failed: {}", err);
+                        continue;
+                    }
+                };
+                let datagram = buf.datagram(bytes);
+                if datagram.truncated {
+                    println!("Session {}: dropped datagram larger than {} bytes", client, buf.max_size());
+                    continue;
+                }
+                if !sessions.contains(client) {
+                    match sessions.open(client, server, None).await {
+                        Ok(()) => println!("Session {}: opened", client),
+                        Err(err) => {
+                            println!("Session {}: unable to open: {}", client, err);
+                            continue;
+                        }
+                    }
+                }
+                sessions.send(client, datagram.data).await;
+            }
+            _ = sweeps.tick() => {
+                for client in sessions.expire() {
+                    println!("Session {}: expired", client);
+                }
+            }
+            _ = ticks.tick() => {
+                println!("To server: {}", outbound.lock().unwrap().stats());
+                println!("To clients: {}", inbound.lock().unwrap().stats());
+            }
+            _ = shutdown.wait() => break,
+        }
+    }
+    println!("To server: {}", outbound.lock().unwrap().stats());
+    println!("To clients: {}", inbound.lock().unwrap().stats());
+    Ok(())
+}
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .bind("0.0.0.0:6142")
+        .destinations(&["127.0.0.1:6150"])
+        .buffer_size(1500)
+        .option("drop", Some("0"))
+        .option("duplicate", Some("0"))
+        .option("corrupt", Some("0"))
+        .option("reorder", Some("0"))
+        .option("delay", Some("0"))
+        .option("jitter", Some("0"))
+        .option("reorder-delay", Some("50"))
+        .option("seed", None)
+        .option("session-timeout", Some("60"))
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
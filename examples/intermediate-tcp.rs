@@ -15,55 +15,289 @@
 //! Application that acts split an incoming message into several other
 //! messages.
 //!
-//! It will accept a connection attempt on port 6142 and open
-//! connections to ports 6150-6152. Any messages sent to 6142 will
-//! then be forwarded to ports 6150-6152 asynchronously. You can test
-//! it by setting up three listening servers using `nc`, start the
-//! intermediate, and then send a message on port 6142.
+//! It will accept connection attempts on port 6142 and forward
+//! anything sent by the clients to ports 6150-6152 asynchronously. You
+//! can test it by setting up three listening servers using `nc`,
+//! start the intermediate, and then send a message on port 6142.
 //!
 //! ```bash
 //! bash-1$ nc -l 6150
 //! bash-2$ nc -l 6151
-//! bash-3$ nc -l 6151
+//! bash-3$ nc -l 6152
 //! bash-4$ cargo run --example intermediate-tcp
 //! bash-5$ cargo run --example sender-tcp 'just a test'
 //! ```
+//!
+//! Each client is handled in a separate task, so several clients can
+//! send at the same time. The connections to the downstream servers
+//! are shared by all clients, and a client or downstream server that
+//! fails is reported without affecting the others.
+//!
+//! The downstream servers do not have to be running when the
+//! intermediate is started: the intermediate reconnects to them when
+//! they are not available or when the connection is lost. Data for a
+//! downstream server that is not connected is buffered, or dropped if
+//! `--unhealthy drop` is given. The status of each downstream server
+//! is printed every ten seconds.
+//!
+//! Each downstream server has its own queue, and `--overflow` decides
+//! what happens when a downstream server cannot keep up and its queue
+//! is full: `drop-oldest` (the default) and `drop-newest` drop data,
+//! `block` waits for room, which stalls the clients until the slowest
+//! downstream server has caught up, and `disconnect` closes the
+//! downstream.
+//!
+//! By default, everything is sent to all destinations, but the relay
+//! can also work as a load balancer that picks one destination for
+//! each client using `--routing`: `round-robin`, `least-connections`,
+//! `hash` (consistent hashing on the source address), or `weighted`
+//! (random, with the weights given using `--weights`, for example
+//! `--weights 3,1,1`). The random choices can be repeated by giving a
+//! seed using `--seed`. Downstream servers that are not connected are
+//! skipped, unless none of them are connected.
+//!
+//! With `--mode proxy`, the intermediate instead works as a proxy in
+//! front of request/response services. Each client gets its own
+//! connection to one destination, picked using the routing (which is
+//! `round-robin` by default in this mode), and anything the
+//! destination sends back is copied to the client. When either side
+//! closes its write direction, this is passed on to the other side,
+//! and the connections are closed if they are idle for the number of
+//! seconds given by `--idle-timeout`.
+//!
+//! ```bash
+//! $ cargo run --example intermediate-tcp -- --mode proxy --idle-timeout 10
+//! ```
+//!
+//! The listening address and the destinations can be changed using
+//! `--bind` and `--dest`:
+//!
+//! ```bash
+//! $ cargo run --example intermediate-tcp -- --bind 127.0.0.1:7000 --dest 127.0.0.1:7001
+//! ```
 
-use futures::prelude::*;
+use bytes::Bytes;
+use futures::future;
 use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::prelude::*;
+use tokio::stream::StreamExt;
+use tokio::time::interval;
+use tokio_examples::config::{Config, ConfigError};
+use tokio_examples::proxy::Proxy;
+use tokio_examples::queue::OverflowPolicy;
+use tokio_examples::relay::{Downstream, DownstreamOptions, UnhealthyPolicy};
+use tokio_examples::routing::{Mode, Route, Router};
 
-#[tokio::main(core_threads = 5)]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let mut listener = TcpListener::bind("127.0.0.1:6142").await?;
+// Number of chunks of data that can wait to be written to each
+// downstream server.
+const QUEUE_SIZE: usize = 32;
 
-    println!("Listening on: {}", listener.local_addr()?);
-    while let Ok((mut socket, addr)) = listener.accept().await {
-        println!("Accepting: {}", addr);
-        let mut destinations = Vec::new();
-        destinations.push(TcpStream::connect("127.0.0.1:6150").await?);
-        destinations.push(TcpStream::connect("127.0.0.1:6151").await?);
-        destinations.push(TcpStream::connect("127.0.0.1:6152").await?);
+// Interval between printing the status of the downstream servers.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+fn unhealthy_policy(config: &Config) -> Result<UnhealthyPolicy, ConfigError> {
+    match config.get("unhealthy") {
+        Some("buffer") | None => Ok(UnhealthyPolicy::Buffer(QUEUE_SIZE)),
+        Some("drop") => Ok(UnhealthyPolicy::Drop),
+        Some(value) => Err(ConfigError::InvalidValue {
+            name: "--unhealthy".to_string(),
+            value: value.to_string(),
+            reason: "expected 'buffer' or 'drop'".to_string(),
+        }),
+    }
+}
+
+// Print the status of the downstream servers periodically.
+async fn print_stats(downstreams: Vec<Downstream>) {
+    let mut ticks = interval(STATS_INTERVAL);
+    ticks.next().await;
+    while ticks.next().await.is_some() {
+        for downstream in &downstreams {
+            println!("Downstream {}: {}", downstream.addr(), downstream.stats());
+        }
+    }
+}
 
-        let mut buf = [0; 1024];
-        loop {
-            let bytes = socket
-                .read(&mut buf)
-                .await
-                .expect("failed to read data from socket");
+// Mark the connected downstream servers as available for routing. If
+// none of them is connected, the ones that are not closed are used
+// instead, so that the data is buffered until they are back.
+fn update_available(router: &mut Router, downstreams: &[Downstream]) {
+    let any_healthy = downstreams.iter().any(Downstream::is_healthy);
+    for (index, downstream) in downstreams.iter().enumerate() {
+        let available = if any_healthy {
+            downstream.is_healthy()
+        } else {
+            !downstream.is_closed()
+        };
+        router.set_available(index, available);
+    }
+}
 
-            if bytes == 0 {
+// Read data from a client and forward it to the downstream servers.
+async fn handle_client(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    mut downstreams: Vec<Downstream>,
+    buffer_size: usize,
+) {
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let bytes = match socket.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(bytes) => bytes,
+            Err(err) => {
+                println!("Client {}: read failed: {}", addr, err);
                 break;
             }
+        };
+
+        let data = Bytes::copy_from_slice(&buf[0..bytes]);
+        let results = future::join_all(
+            downstreams
+                .iter()
+                .map(|downstream| downstream.send(data.clone())),
+        )
+        .await;
+        for err in results.into_iter().filter_map(Result::err) {
+            println!("Client {}: {}", addr, err);
+        }
+        downstreams.retain(|downstream| !downstream.is_closed());
+        if downstreams.is_empty() {
+            println!("Client {}: all downstream servers are closed", addr);
+            break;
+        }
+    }
+    println!("Closing: {}", addr);
+}
+
+// Connect a client to an upstream server and copy data in both
+// directions until both are done.
+async fn proxy_client(mut socket: TcpStream, addr: SocketAddr, upstream: SocketAddr, proxy: Proxy) {
+    let mut stream = match TcpStream::connect(upstream).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!(
+                "Client {}: unable to connect to {}: {}",
+                addr, upstream, err
+            );
+            return;
+        }
+    };
+    match proxy.run(&mut socket, &mut stream).await {
+        Ok(stats) => println!(
+            "Closing: {} ({} bytes sent, {} bytes received)",
+            addr, stats.sent, stats.received
+        ),
+        Err(err) => println!("Closing: {}: {}", addr, err),
+    }
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let mut listener = TcpListener::bind(config.bind).await?;
+    let mode = config.parse("mode")?.unwrap_or(Mode::Tee);
+    let mut router = Router::configure(
+        mode,
+        config.parse("routing")?,
+        config.destinations.len(),
+        config.parse("weights")?.as_ref(),
+        config.parse("seed")?,
+    )?;
+    let mut proxy = Proxy::new().buffer_size(config.buffer_size);
+    match config.parse("idle-timeout")? {
+        Some(0) => {
+            return Err(ConfigError::InvalidValue {
+                name: "--idle-timeout".to_string(),
+                value: "0".to_string(),
+                reason: "the idle timeout has to be at least one second".to_string(),
+            }
+            .into())
+        }
+        Some(secs) => proxy = proxy.idle_timeout(Duration::from_secs(secs)),
+        None => {}
+    }
 
-            future::join_all(
-                destinations
-                    .iter_mut()
-                    .map(|dest| dest.write_all(&buf[0..bytes])),
-            )
-            .await;
+    // The shared downstream connections are only used when teeing.
+    let mut downstreams = Vec::new();
+    if mode == Mode::Tee {
+        let overflow = config
+            .parse("overflow")?
+            .unwrap_or(OverflowPolicy::DropOldest);
+        let options = DownstreamOptions::new()
+            .capacity(QUEUE_SIZE)
+            .overflow(overflow)
+            .unhealthy_policy(unhealthy_policy(&config)?);
+        downstreams = config
+            .destinations
+            .iter()
+            .map(|&dest| Downstream::spawn(dest, options.clone()))
+            .collect();
+        tokio::spawn(print_stats(downstreams.clone()));
+    }
+
+    println!("Listening on: {}", listener.local_addr()?);
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                println!("Accept failed: {}", err);
+                continue;
+            }
+        };
+        println!("Accepting: {}", addr);
+        if mode == Mode::Tee {
+            update_available(&mut router, &downstreams);
+        }
+        match (mode, router.route(addr)) {
+            (Mode::Tee, Some(Route::All)) => {
+                let targets = downstreams
+                    .iter()
+                    .filter(|downstream| !downstream.is_closed())
+                    .cloned()
+                    .collect();
+                tokio::spawn(handle_client(socket, addr, targets, config.buffer_size));
+            }
+            (Mode::Tee, Some(Route::One(lease))) => {
+                let targets = vec![downstreams[lease.index()].clone()];
+                let client = handle_client(socket, addr, targets, config.buffer_size);
+                tokio::spawn(async move {
+                    client.await;
+                    // The client counts as connected to the
+                    // destination until it is done.
+                    drop(lease);
+                });
+            }
+            (Mode::Proxy, Some(Route::One(lease))) => {
+                let upstream = config.destinations[lease.index()];
+                let client = proxy_client(socket, addr, upstream, proxy.clone());
+                tokio::spawn(async move {
+                    client.await;
+                    drop(lease);
+                });
+            }
+            (Mode::Proxy, Some(Route::All)) => {
+                unreachable!("broadcast is rejected in proxy mode")
+            }
+            (_, None) => println!("Closing: {}: no downstream server available", addr),
         }
     }
-    Ok(())
+}
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .bind("127.0.0.1:6142")
+        .destinations(&["127.0.0.1:6150", "127.0.0.1:6151", "127.0.0.1:6152"])
+        .buffer_size(1024)
+        .threads(5)
+        .option("unhealthy", Some("buffer"))
+        .option("overflow", Some("drop-oldest"))
+        .option("mode", Some("tee"))
+        .option("idle-timeout", Some("60"))
+        .option("routing", None)
+        .option("weights", None)
+        .option("seed", None)
+        .load()?;
+    config.runtime()?.block_on(run(config))
 }
@@ -23,16 +23,96 @@
 //! port 6142.
 //!
 //! ```bash
-//! bash-1$ nc -l 6150
-//! bash-2$ nc -l 6151
-//! bash-3$ nc -l 6151
-//! bash-4$ cargo run --bin intermediate-udp
-//! bash-5$ cargo run --bin sender-udp 'just a test'
+//! bash-1$ nc -ul 6150
+//! bash-2$ nc -ul 6151
+//! bash-3$ nc -ul 6152
+//! bash-4$ cargo run --example intermediate-udp
+//! bash-5$ cargo run --example sender-udp 'just a test'
 //! ```
+//!
+//! Each destination has its own queue and task sending to it, so a
+//! slow destination does not hold up the other ones. The `--overflow`
+//! option decides what happens when the queue of a destination is
+//! full: `block` waits for room, `drop-oldest` (the default) and
+//! `drop-newest` drop datagrams, and `disconnect` stops sending to
+//! the destination.
+//!
+//! By default, everything is sent to all destinations, but the relay
+//! can also work as a load balancer that picks one destination for
+//! each datagram using `--routing`: `round-robin`, `least-connections`,
+//! `hash` (consistent hashing on the source address), or `weighted`
+//! (random, with the weights given using `--weights`, for example
+//! `--weights 3,1,1`). The random choices can be repeated by giving a
+//! seed using `--seed`. Destinations that have been disconnected are
+//! skipped.
+//!
+//! With `--mode proxy`, the intermediate instead works as a proxy
+//! that lets the destinations reply to the clients. Each client gets
+//! a session with its own socket connected to one destination, picked
+//! using the routing (which is `round-robin` by default in this mode),
+//! and datagrams received on that socket are sent back to the client.
+//! Sessions without traffic in either direction for the number of
+//! seconds given by `--session-timeout` are closed.
+//!
+//! The listening address and the destinations can be changed using
+//! `--bind` and `--dest`, and the maximum datagram size using
+//! `--buffer-size` (at most 65507 bytes). Datagrams that are larger
+//! than this are reported and dropped, and statistics on the sizes of
+//! the datagrams from the clients are printed every ten seconds.
 
+use bytes::Bytes;
 use futures::prelude::*;
 use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::time::interval;
+use tokio_examples::config::Config;
+use tokio_examples::datagram::{Datagram, DatagramBuffer, SizeStats};
+use tokio_examples::queue::{self, OverflowPolicy};
+use tokio_examples::routing::{Lease, Mode, Route, Router};
+use tokio_examples::session::Sessions;
+
+// Number of datagrams that can wait to be sent to each destination.
+const QUEUE_SIZE: usize = 32;
+
+// Interval between checking for idle sessions.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// Interval between printing the datagram size statistics.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Statistics on the datagrams received from the clients.
+struct ClientStats {
+    sizes: SizeStats,
+    reported: Instant,
+}
+
+impl ClientStats {
+    fn new() -> Self {
+        Self {
+            sizes: SizeStats::new(),
+            reported: Instant::now(),
+        }
+    }
+
+    // Record a datagram and return true if it should be forwarded.
+    fn record(&mut self, datagram: &Datagram<'_>, client: SocketAddr) -> bool {
+        self.sizes.record(datagram);
+        if datagram.truncated {
+            println!(
+                "Client {}: dropped datagram larger than {} bytes",
+                client,
+                datagram.data.len()
+            );
+        }
+        if self.reported.elapsed() >= STATS_INTERVAL {
+            println!("{}", self.sizes);
+            self.reported = Instant::now();
+        }
+        !datagram.truncated
+    }
+}
 
 async fn make_socket<A: ToSocketAddrs>(addr: A) -> tokio::io::Result<UdpSocket> {
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
@@ -40,23 +120,177 @@ async fn make_socket<A: ToSocketAddrs>(addr: A) -> tokio::io::Result<UdpSocket>
     Ok(socket)
 }
 
-#[tokio::main(core_threads = 5)]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let mut socket = UdpSocket::bind("0.0.0.0:6142").await?;
-    let mut buf = [0; 1024];
+// Send the datagrams in the queue to a destination. The lease, if
+// any, is held until the datagram is sent, so the least-connections
+// routing picks the destination with the fewest datagrams waiting.
+async fn forward(
+    mut socket: UdpSocket,
+    dest: SocketAddr,
+    mut queue: queue::Receiver<(Bytes, Option<Lease>)>,
+) {
+    while let Some((data, _lease)) = queue.recv().await {
+        if let Err(err) = socket.send(&data).await {
+            println!("Destination {}: send failed: {}", dest, err);
+        }
+    }
+    println!("Destination {}: closed", dest);
+}
+
+// Forward datagrams from the clients to the destinations picked by the
+// router.
+async fn run_tee(
+    mut socket: UdpSocket,
+    mut router: Router,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut buf = DatagramBuffer::new(config.buffer_size)?;
+    let mut stats = ClientStats::new();
+    let overflow = config
+        .parse("overflow")?
+        .unwrap_or(OverflowPolicy::DropOldest);
     let mut destinations = Vec::new();
-    destinations.push(make_socket("127.0.0.1:6150").await?);
-    destinations.push(make_socket("127.0.0.1:6151").await?);
-    destinations.push(make_socket("127.0.0.1:6152").await?);
+    for &dest in &config.destinations {
+        let (sender, receiver) = queue::bounded(QUEUE_SIZE, overflow);
+        tokio::spawn(forward(make_socket(dest).await?, dest, receiver));
+        destinations.push((dest, sender));
+    }
 
-    println!("Listening on: {}", socket.local_addr()?);
-    while let Ok((bytes, _addr)) = socket.recv_from(&mut buf).await {
-        future::join_all(
-            destinations
-                .iter_mut()
-                .map(|dest| dest.send(&buf[0..bytes])),
-        )
-        .await;
+    while let Ok((bytes, addr)) = socket.recv_from(buf.recv_buf()).await {
+        let datagram = buf.datagram(bytes);
+        if !stats.record(&datagram, addr) {
+            continue;
+        }
+        let data = Bytes::copy_from_slice(datagram.data);
+        // Sending fails only if the destination is disconnected, and
+        // then the router stops picking it.
+        let failed = match router.route(addr) {
+            Some(Route::All) => {
+                let open: Vec<usize> = (0..destinations.len())
+                    .filter(|&index| router.is_available(index))
+                    .collect();
+                let results = future::join_all(
+                    open.iter()
+                        .map(|&index| destinations[index].1.send((data.clone(), None))),
+                )
+                .await;
+                open.into_iter()
+                    .zip(results)
+                    .filter_map(|(index, result)| result.err().map(|_| index))
+                    .collect()
+            }
+            Some(Route::One(lease)) => {
+                let index = lease.index();
+                match destinations[index].1.send((data, Some(lease))).await {
+                    Ok(()) => vec![],
+                    Err(_) => vec![index],
+                }
+            }
+            None => {
+                println!("Client {}: no destination available", addr);
+                vec![]
+            }
+        };
+        for index in failed {
+            let (dest, sender) = &destinations[index];
+            println!(
+                "Destination {}: disconnected after dropping {} datagrams",
+                dest,
+                sender.dropped()
+            );
+            router.set_available(index, false);
+        }
     }
     Ok(())
 }
+
+// Forward datagrams from each client to its upstream server, and the
+// replies back to the client.
+async fn run_proxy(
+    socket: UdpSocket,
+    mut router: Router,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut buf = DatagramBuffer::new(config.buffer_size)?;
+    let mut stats = ClientStats::new();
+    let timeout = Duration::from_secs(config.parse("session-timeout")?.unwrap_or(60));
+    let (mut socket, socket_send) = socket.split();
+    let mut sessions = Sessions::new(socket_send, buf.clone()).timeout(timeout);
+    let mut sweeps = interval(SWEEP_INTERVAL);
+    loop {
+        tokio::select! {
+            result = socket.recv_from(buf.recv_buf()) => {
+                // Errors can be caused by a single client, for example
+                // when a reply to it was refused, so they do not stop
+                // the proxy.
+                let (bytes, client) = match result {
+                    Ok(received) => received,
+                    Err(err) => {
+                        println!("Receive failed: {}", err);
+                        continue;
+                    }
+                };
+                let datagram = buf.datagram(bytes);
+                if !stats.record(&datagram, client) {
+                    continue;
+                }
+                if !sessions.contains(client) {
+                    let lease = match router.route(client) {
+                        Some(Route::One(lease)) => lease,
+                        Some(Route::All) => unreachable!("broadcast is rejected in proxy mode"),
+                        None => {
+                            println!("Session {}: no destination available", client);
+                            continue;
+                        }
+                    };
+                    let dest = config.destinations[lease.index()];
+                    match sessions.open(client, dest, Some(lease)).await {
+                        Ok(()) => println!("Session {}: opened to {}", client, dest),
+                        Err(err) => {
+                            println!("Session {}: unable to open: {}", client, err);
+                            continue;
+                        }
+                    }
+                }
+                sessions.send(client, datagram.data).await;
+            }
+            _ = sweeps.tick() => {
+                for client in sessions.expire() {
+                    println!("Session {}: expired", client);
+                }
+            }
+        }
+    }
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind(config.bind).await?;
+    let mode = config.parse("mode")?.unwrap_or(Mode::Tee);
+    let router = Router::configure(
+        mode,
+        config.parse("routing")?,
+        config.destinations.len(),
+        config.parse("weights")?.as_ref(),
+        config.parse("seed")?,
+    )?;
+    println!("Listening on: {}", socket.local_addr()?);
+    match mode {
+        Mode::Tee => run_tee(socket, router, &config).await,
+        Mode::Proxy => run_proxy(socket, router, &config).await,
+    }
+}
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .bind("0.0.0.0:6142")
+        .destinations(&["127.0.0.1:6150", "127.0.0.1:6151", "127.0.0.1:6152"])
+        .buffer_size(1024)
+        .threads(5)
+        .option("overflow", Some("drop-oldest"))
+        .option("mode", Some("tee"))
+        .option("session-timeout", Some("60"))
+        .option("routing", None)
+        .option("weights", None)
+        .option("seed", None)
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
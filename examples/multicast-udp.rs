@@ -12,64 +12,273 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
-//use futures::future;
-use bytes::Bytes;
-use futures::executor::block_on_stream;
-use futures::stream::FuturesUnordered;
-use std::env;
+//! Application that receives packets on one socket and sends a copy
+//! of each packet to all destinations.
+//!
+//! ```bash
+//! $ cargo run --example multicast-udp -- --dest 127.0.0.1:6150 --dest 127.0.0.1:6151
+//! ```
+//!
+//! The copies are sent concurrently, and a destination that fails is
+//! put in quarantine for ten seconds, without affecting the other
+//! destinations.
+//!
+//! It can also use a real IP multicast group, given using `--group`.
+//! With `--mode subscribe`, it joins the group and prints all packets
+//! sent to it until Ctrl-C is pressed. With `--mode publish`, it sends
+//! the arguments to the group, or, if there are no arguments, a copy
+//! of each packet received on the bind address. Since loopback is
+//! enabled by default, this can be tested on a single host:
+//!
+//! ```bash
+//! bash-1$ cargo run --example multicast-udp -- --mode subscribe --group 239.255.0.1:6160
+//! bash-2$ cargo run --example multicast-udp -- --mode subscribe --group 239.255.0.1:6160
+//! bash-3$ cargo run --example multicast-udp -- --mode publish --group 239.255.0.1:6160 hello
+//! ```
+//!
+//! IPv6 groups work the same way, for example `--group [ff02::1234]:6160`.
+//! The TTL (hop limit for IPv6) is set using `--ttl`, loopback can be
+//! turned off using `--loopback false`, and the interface is given
+//! using `--interface`, as an address for IPv4 and as an interface
+//! index for IPv6.
+//!
+//! The maximum packet size is given using `--buffer-size`, and can be
+//! at most 65507 bytes. Larger packets are reported and dropped when
+//! forwarding, and reported as truncated by the subscriber, which also
+//! prints statistics on the packet sizes when it leaves the group.
+
+use futures::future;
 use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::io;
 use tokio::net::UdpSocket;
+use tokio_examples::config::{Config, ConfigError};
+use tokio_examples::datagram::{DatagramBuffer, SizeStats};
+use tokio_examples::multicast::Multicast;
+use tokio_examples::Shutdown;
+
+// What to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // Send a copy of each packet to all destinations.
+    Fanout,
+
+    // Send packets to a multicast group.
+    Publish,
+
+    // Receive packets sent to a multicast group.
+    Subscribe,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fanout" => Ok(Mode::Fanout),
+            "publish" => Ok(Mode::Publish),
+            "subscribe" => Ok(Mode::Subscribe),
+            _ => Err(format!("unknown mode '{}'", s)),
+        }
+    }
+}
+
+// Period a destination is quarantined after a failed send.
+const QUARANTINE_PERIOD: Duration = Duration::from_secs(10);
 
 struct Connection {
     socket: UdpSocket,
     addr: SocketAddr,
 }
 
-/// Multicast the packet to all the sockets and collect sockets after.
+// Connection that is not used until the quarantine period is over.
+struct Quarantined {
+    conn: Connection,
+    until: Instant,
+}
+
+// Send the packet to all the connections concurrently and return the
+// result of each send, in the same order as the connections.
 async fn multicast_packet(
-    packet: Bytes,
-    addresses: Vec<Connection>,
-) -> io::Result<Vec<Connection>> {
-    let tasks: FuturesUnordered<_> = addresses
-        .into_iter()
-        .map(|mut conn| {
-            let packet = packet.clone();
-            async move {
-                conn.socket.send_to(&packet, conn.addr).await?;
-                Ok(conn)
+    packet: &[u8],
+    connections: &mut [Connection],
+) -> Vec<(SocketAddr, io::Result<usize>)> {
+    future::join_all(connections.iter_mut().map(|conn| async move {
+        let result = conn.socket.send_to(packet, &conn.addr).await;
+        (conn.addr, result)
+    }))
+    .await
+}
+
+fn make_group(config: &Config) -> Result<Multicast, Box<dyn Error>> {
+    let addr = config
+        .parse("group")?
+        .ok_or("no multicast group, use --group to give one")?;
+    let mut group = Multicast::new(addr);
+    if let Some(ttl) = config.parse("ttl")? {
+        group = group.ttl(ttl);
+    }
+    if let Some(loopback) = config.parse("loopback")? {
+        group = group.loopback(loopback);
+    }
+    if let Some(value) = config.get("interface") {
+        // An IPv4 group needs the address of the interface, and an
+        // IPv6 group needs its index.
+        let invalid = |reason: &str| ConfigError::InvalidValue {
+            name: "--interface".to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        };
+        group = match (addr, value.parse::<Ipv4Addr>(), value.parse::<u32>()) {
+            (SocketAddr::V4(_), Ok(interface), _) => group.interface_v4(interface),
+            (SocketAddr::V6(_), _, Ok(index)) => group.interface_v6(index),
+            (SocketAddr::V4(_), ..) => {
+                return Err(Box::new(invalid(
+                    "expected an IPv4 address for an IPv4 group",
+                )))
+            }
+            (SocketAddr::V6(_), ..) => {
+                return Err(Box::new(invalid(
+                    "expected an interface index for an IPv6 group",
+                )))
             }
-        })
-        .collect();
-    block_on_stream(tasks).collect()
+        };
+    }
+    Ok(group)
 }
 
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let mut incoming = UdpSocket::bind("0.0.0.0:6142").await?;
+async fn publish(config: Config) -> Result<(), Box<dyn Error>> {
+    let group = make_group(&config)?;
+    let mut socket = group.publisher()?;
+    if !config.args.is_empty() {
+        for message in &config.args {
+            socket.send_to(message.as_bytes(), &group.addr()).await?;
+        }
+        return Ok(());
+    }
+
+    let mut incoming = UdpSocket::bind(config.bind).await?;
+    let mut buf = DatagramBuffer::new(config.buffer_size)?;
+    println!("Publishing to {}", group.addr());
+    loop {
+        let bytes = incoming.recv(buf.recv_buf()).await?;
+        let datagram = buf.datagram(bytes);
+        if datagram.truncated {
+            println!("Dropped packet larger than {} bytes", buf.max_size());
+            continue;
+        }
+        socket.send_to(datagram.data, &group.addr()).await?;
+    }
+}
+
+async fn subscribe(config: Config) -> Result<(), Box<dyn Error>> {
+    let group = make_group(&config)?;
+    let mut subscription = group.subscribe()?;
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_ctrl_c();
+
+    let mut buf = DatagramBuffer::new(config.buffer_size)?;
+    let mut stats = SizeStats::new();
+    println!("Joined {}", group.addr());
+    loop {
+        tokio::select! {
+            result = subscription.recv_from(buf.recv_buf()) => {
+                let (bytes, addr) = result?;
+                let datagram = buf.datagram(bytes);
+                stats.record(&datagram);
+                let text = String::from_utf8_lossy(datagram.data);
+                if datagram.truncated {
+                    println!("{}: {} (truncated)", addr, text);
+                } else {
+                    println!("{}: {}", addr, text);
+                }
+            }
+            _ = shutdown.wait() => break,
+        }
+    }
+    subscription.leave()?;
+    println!("Left {}", group.addr());
+    println!("{}", stats);
+    Ok(())
+}
+
+async fn fanout(config: Config) -> Result<(), Box<dyn Error>> {
+    if config.destinations.is_empty() {
+        return Err("no destinations, use --dest to add one".into());
+    }
+    let mut incoming = UdpSocket::bind(config.bind).await?;
     let mut outbound = vec![];
-    for arg in env::args().skip(1) {
+    for &addr in &config.destinations {
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let addr = arg.parse()?;
         outbound.push(Connection { socket, addr });
     }
 
+    let mut quarantine: Vec<Quarantined> = Vec::new();
+    let mut buf = DatagramBuffer::new(config.buffer_size)?;
     loop {
-        let mut buf = [0; 1500];
         println!("Waiting for packet");
-        match incoming.recv(&mut buf).await {
-            Ok(bytes) if bytes == 0 => break,
-            Ok(bytes) => {
-                let packet = Bytes::copy_from_slice(&buf[0..bytes]);
-                outbound = multicast_packet(packet, outbound).await?;
-            }
+        let datagram = match incoming.recv(buf.recv_buf()).await {
+            Ok(0) => break,
+            Ok(bytes) => buf.datagram(bytes),
             Err(err) => {
                 println!("Error: {}", err);
                 break;
             }
+        };
+        if datagram.truncated {
+            println!("Dropped packet larger than {} bytes", buf.max_size());
+            continue;
+        }
+
+        // Give destinations that are done with their quarantine
+        // another chance.
+        let now = Instant::now();
+        let (released, remaining) = quarantine.into_iter().partition(|q| q.until <= now);
+        quarantine = remaining;
+        for Quarantined { conn, .. } in released {
+            println!("Destination {}: released from quarantine", conn.addr);
+            outbound.push(conn);
+        }
+
+        let results = multicast_packet(datagram.data, &mut outbound).await;
+        // Remove in reverse order so that the remaining indexes are
+        // still valid.
+        for (index, (addr, result)) in results.into_iter().enumerate().rev() {
+            if let Err(err) = result {
+                println!(
+                    "Destination {}: {}, quarantined for {:?}",
+                    addr, err, QUARANTINE_PERIOD
+                );
+                quarantine.push(Quarantined {
+                    conn: outbound.remove(index),
+                    until: now + QUARANTINE_PERIOD,
+                });
+            }
         }
     }
 
     Ok(())
 }
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    match config.parse("mode")?.unwrap_or(Mode::Fanout) {
+        Mode::Fanout => fanout(config).await,
+        Mode::Publish => publish(config).await,
+        Mode::Subscribe => subscribe(config).await,
+    }
+}
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .bind("0.0.0.0:6142")
+        .buffer_size(1500)
+        .option("mode", Some("fanout"))
+        .option("group", None)
+        .option("ttl", Some("1"))
+        .option("loopback", Some("true"))
+        .option("interface", None)
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
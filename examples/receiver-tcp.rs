@@ -15,40 +15,61 @@
 //! Example application that receives messages over TCP.
 //!
 //! This application will receive messages over TCP and print them to
-//! the terminal. It will listen on port 6148 and spawn a session for
-//! any incoming TCP connection, read the messages until the
-//! connection is shut down.
+//! the terminal. It will listen on port 6142, or the address given
+//! using `--bind`, and spawn a session for any incoming TCP
+//! connection, read the messages until the connection is shut down.
+//!
+//! The messages have to be framed the same way as `sender-tcp` frames
+//! them, which by default is to prefix each message with its
+//! length. Use `--framing newline` to read newline-terminated messages
+//! instead.
+//!
+//! Messages can be at most 8 MiB, or the number of bytes given using
+//! `--max-length`. If a longer message is received, or a line without
+//! a newline grows past the limit, the error is printed and the
+//! connection is closed:
+//!
+//! ```bash
+//! bash-1$ cargo run --example receiver-tcp -- --framing newline --max-length 10
+//! bash-2$ echo 'this line is too long' | nc 127.0.0.1 6142
+//! ```
 
+use futures::StreamExt;
 use std::error::Error;
-use std::str::from_utf8;
-use tokio;
-use tokio::io::AsyncReadExt;
 use tokio::net::TcpListener;
+use tokio_examples::config::Config;
+use tokio_examples::framing::{framed_with_max_length, Framing, MAX_MESSAGE_LENGTH};
 
-#[tokio::main(core_threads = 5)]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let mut listener = TcpListener::bind("127.0.0.1:6142").await?;
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let framing = config.parse("framing")?.unwrap_or(Framing::LengthDelimited);
+    let max_length = config.parse("max-length")?.unwrap_or(MAX_MESSAGE_LENGTH);
+    let mut listener = TcpListener::bind(config.bind).await?;
     println!("Listening on: {}", listener.local_addr()?);
-    while let Ok((mut socket, addr)) = listener.accept().await {
+    while let Ok((socket, addr)) = listener.accept().await {
         println!("Accepting: {}", addr);
         tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            loop {
-                let n = socket
-                    .read(&mut buf)
-                    .await
-                    .expect("failed to read data from socket");
-
-                if n == 0 {
-                    return;
-                }
-
-                match from_utf8(&buf) {
+            let mut messages = framed_with_max_length(socket, framing, max_length);
+            while let Some(result) = messages.next().await {
+                match result {
                     Ok(msg) => println!("received: {}", msg),
-                    Err(err) => println!("error: {}", err),
+                    Err(err) => {
+                        println!("error: {}", err);
+                        break;
+                    }
                 }
             }
+            println!("Closing: {}", addr);
         });
     }
     Ok(())
 }
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .bind("127.0.0.1:6142")
+        .threads(5)
+        .option("framing", None)
+        .option("max-length", None)
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
@@ -12,21 +12,134 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
+//! Example application that receives messages over UDP.
+//!
+//! By default, it listens on 0.0.0.0:6142 and accepts datagrams of up
+//! to 1024 bytes, which can be changed using `--bind` and
+//! `--buffer-size`. The buffer size can be at most 65507 bytes, which
+//! is the largest UDP payload over IPv4. Datagrams that are larger
+//! than the buffer size are reported as truncated.
+//!
+//! Statistics on the sizes of the received datagrams are printed every
+//! ten seconds and when Ctrl-C is pressed.
+//!
+//! With `--mode reliable`, it receives messages sent using the reliable
+//! protocol, for example by `sender-udp --mode reliable`. Each message
+//! is then printed once, in the order it was sent, together with its
+//! sequence number, and the statistics show the number of duplicate
+//! and reordered messages.
+
 use std::error::Error;
-use std::str::from_utf8;
-use tokio;
+use std::str::{from_utf8, FromStr};
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::stream::StreamExt;
+use tokio::time::interval;
+use tokio_examples::config::Config;
+use tokio_examples::datagram::{DatagramBuffer, SizeStats};
+use tokio_examples::reliable::ReliableReceiver;
+use tokio_examples::Shutdown;
+
+// Interval between printing the statistics.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+// How messages are received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // Print each datagram as it is received.
+    Raw,
+
+    // Receive messages using the reliable protocol.
+    Reliable,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Mode::Raw),
+            "reliable" => Ok(Mode::Reliable),
+            _ => Err(format!("unknown mode '{}'", s)),
+        }
+    }
+}
 
-#[tokio::main(core_threads = 5)]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let mut socket = UdpSocket::bind("0.0.0.0:6142").await?;
-    let mut buf = [0; 1024];
-    while let Ok((bytes, addr)) = socket.recv_from(&mut buf).await {
-        print!("Packet of {} bytes from {}: ", bytes, addr);
-        match from_utf8(&buf[0..bytes]) {
-            Ok(msg) => println!("{}", msg),
-            Err(err) => println!("ERROR {}", err),
+async fn run_raw(
+    mut socket: UdpSocket,
+    mut buf: DatagramBuffer,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let mut stats = SizeStats::new();
+    let mut ticks = interval(STATS_INTERVAL);
+    ticks.next().await;
+    loop {
+        tokio::select! {
+            result = socket.recv_from(buf.recv_buf()) => {
+                let (bytes, addr) = result?;
+                let datagram = buf.datagram(bytes);
+                stats.record(&datagram);
+                if datagram.truncated {
+                    print!("Truncated packet of more than {} bytes from {}: ", buf.max_size(), addr);
+                } else {
+                    print!("Packet of {} bytes from {}: ", bytes, addr);
+                }
+                match from_utf8(datagram.data) {
+                    Ok(msg) => println!("{}", msg),
+                    Err(err) => println!("ERROR {}", err),
+                }
+            }
+            _ = ticks.next() => println!("{}", stats),
+            _ = shutdown.wait() => break,
         }
     }
+    println!("{}", stats);
     Ok(())
 }
+
+async fn run_reliable(
+    socket: UdpSocket,
+    buf: DatagramBuffer,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let mut receiver = ReliableReceiver::new(socket, buf);
+    let mut ticks = interval(STATS_INTERVAL);
+    ticks.next().await;
+    loop {
+        tokio::select! {
+            result = receiver.recv() => {
+                let delivery = result?;
+                print!("Message {} from {}: ", delivery.seq, delivery.addr);
+                match from_utf8(&delivery.data) {
+                    Ok(msg) => println!("{}", msg),
+                    Err(err) => println!("ERROR {}", err),
+                }
+            }
+            _ = ticks.next() => println!("{}", receiver.stats()),
+            _ = shutdown.wait() => break,
+        }
+    }
+    println!("{}", receiver.stats());
+    Ok(())
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind(config.bind).await?;
+    let buf = DatagramBuffer::new(config.buffer_size)?;
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_ctrl_c();
+    match config.parse("mode")?.unwrap_or(Mode::Raw) {
+        Mode::Raw => run_raw(socket, buf, shutdown).await,
+        Mode::Reliable => run_reliable(socket, buf, shutdown).await,
+    }
+}
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .bind("0.0.0.0:6142")
+        .buffer_size(1024)
+        .threads(5)
+        .option("mode", Some("raw"))
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
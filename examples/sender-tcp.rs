@@ -12,36 +12,57 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
-//! Example of sending a message over TCP using Tokio.
+//! Example of sending messages over TCP using Tokio.
 //!
-//! You can either start the `receiver-tcp` or listen for the message
+//! You can either start the `receiver-tcp` or listen for the messages
 //! using `netcat`:
 //!
 //! ```bash
 //! $ nc -l 6142
 //! ```
 //!
-//! To send a message, the command accept a message on the command
-//! line that it will send to the port 6142 using a TCP connection.
+//! To send messages, the command accept messages on the command line
+//! that it will send to the port 6142 over a single TCP connection.
 //!
 //! ```bash
-//! $ cargo run --example sender-tcp 'just a test'
+//! $ cargo run --example sender-tcp 'just a test' 'another test'
 //! ```
 //!
 //! If no message is provided, "hello world" will be used.
+//!
+//! Each message is framed so that the receiver can tell the messages
+//! apart. By default, each message is prefixed with its length, but
+//! you can use `--framing newline` to end each message with a newline
+//! instead, which is more readable if you are using `netcat`. Use
+//! `--dest` to connect to another address.
 
-use std::env;
+use futures::SinkExt;
 use std::error::Error;
-use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio_examples::config::Config;
+use tokio_examples::framing::{framed, Framing};
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let framing = config.parse("framing")?.unwrap_or(Framing::LengthDelimited);
+    let mut messages = config.args.clone();
+    if messages.is_empty() {
+        messages.push("hello world".to_string());
+    }
 
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let message = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "hello world".to_string());
-    let mut stream = TcpStream::connect("127.0.0.1:6142").await?;
-    let result = stream.write(message.as_bytes()).await;
-    println!("wrote to stream: result={:?}", result);
+    let addr = config.destinations.first().ok_or("no destination")?;
+    let stream = TcpStream::connect(addr).await?;
+    let mut framed = framed(stream, framing);
+    for message in messages {
+        let result = framed.send(message).await;
+        println!("wrote to stream: result={:?}", result);
+    }
     Ok(())
 }
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .destinations(&["127.0.0.1:6142"])
+        .option("framing", None)
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
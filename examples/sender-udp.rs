@@ -12,19 +12,118 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
-use std::env;
+//! Example of sending a message over UDP using Tokio.
+//!
+//! The message is taken from the command line and sent to all
+//! destinations, which by default is 127.0.0.1:6142. Use `--dest` to
+//! send it somewhere else.
+//!
+//! ```bash
+//! $ cargo run --example sender-udp -- --dest 127.0.0.1:6143 'just a test'
+//! ```
+//!
+//! If several messages are given, they are all sent, and `--count`
+//! sends each message that many times, with a number appended to it.
+//!
+//! With `--mode reliable`, the messages are sent using the reliable
+//! protocol in `tokio_examples::reliable`, which retransmits messages
+//! until they are acknowledged by `receiver-udp --mode reliable`. The
+//! retransmission timeout in milliseconds is set using `--timeout`,
+//! the maximum number of retransmissions using `--retries`, and the
+//! number of unacknowledged messages using `--window`.
+//!
+//! ```bash
+//! bash-1$ cargo run --example receiver-udp -- --mode reliable
+//! bash-2$ cargo run --example sender-udp -- --mode reliable --count 100 'just a test'
+//! ```
+
+use bytes::Bytes;
 use std::error::Error;
-use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio_examples::config::Config;
+use tokio_examples::reliable::ReliableSender;
+
+// How messages are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // Send each message in a datagram and hope for the best.
+    Raw,
+
+    // Send the messages using the reliable protocol.
+    Reliable,
+}
+
+impl FromStr for Mode {
+    type Err = String;
 
-#[tokio::main]
-pub async fn main() -> Result<(), Box<dyn Error>> {
-    let message = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "hello world".to_string());
-    let addr: SocketAddr = "127.0.0.1:6142".parse()?;
-    let mut socket = UdpSocket::bind("0.0.0.0:0").await?;
-    let result = socket.send_to(message.as_bytes(), &addr).await?;
-    println!("wrote to stream: result={:?}", result);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Mode::Raw),
+            "reliable" => Ok(Mode::Reliable),
+            _ => Err(format!("unknown mode '{}'", s)),
+        }
+    }
+}
+
+fn make_messages(config: &Config) -> Result<Vec<Bytes>, Box<dyn Error>> {
+    let mut args = config.args.clone();
+    if args.is_empty() {
+        args.push("hello world".to_string());
+    }
+    let count = config.parse("count")?.unwrap_or(1);
+    let mut messages = Vec::new();
+    for arg in &args {
+        if count == 1 {
+            messages.push(Bytes::from(arg.clone()));
+        } else {
+            messages.extend((0..count).map(|i| Bytes::from(format!("{} {}", arg, i))));
+        }
+    }
+    Ok(messages)
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let messages = make_messages(&config)?;
+    let mut socket = UdpSocket::bind(config.bind).await?;
+    match config.parse("mode")?.unwrap_or(Mode::Raw) {
+        Mode::Raw => {
+            for addr in &config.destinations {
+                for message in &messages {
+                    let result = socket.send_to(message, addr).await?;
+                    println!("wrote to stream: result={:?}", result);
+                }
+            }
+        }
+        Mode::Reliable => {
+            let mut sender = ReliableSender::new();
+            if let Some(window) = config.parse("window")? {
+                sender = sender.window(window);
+            }
+            if let Some(timeout) = config.parse("timeout")? {
+                sender = sender.timeout(Duration::from_millis(timeout));
+            }
+            if let Some(retries) = config.parse("retries")? {
+                sender = sender.retries(retries);
+            }
+            for &addr in &config.destinations {
+                let stats = sender.send(&mut socket, addr, messages.clone()).await?;
+                println!("delivered to {}: {}", addr, stats);
+            }
+        }
+    }
     Ok(())
 }
+
+pub fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::new()
+        .destinations(&["127.0.0.1:6142"])
+        .option("mode", Some("raw"))
+        .option("count", Some("1"))
+        .option("window", None)
+        .option("timeout", None)
+        .option("retries", None)
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
@@ -12,148 +12,196 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 
-// Since it is not possible to clone sockets, it is necessary to have
-// dedicated futures for writing the sockets. Reading the sockets
-// should still go to a single future, so this is to handle multiple
-// futures that want to send data.
+// Since it is not possible to clone sockets, the socket is handed
+// over to a `SocketManager`, which has dedicated tasks for reading and
+// writing the socket. Any number of tasks can then send on the socket
+// using a cloned `SocketHandle`, while the received datagrams go to a
+// single stream.
 //
 // In the example, we will spawn one task that read data from the
-// socket and send back a processed response, one task that has to
-// role of sending out packets accepted on a channel, and a periodic
-// thread that send another message on the UDP socket using the
-// channel.
+// socket and send back a processed response, and a periodic task that
+// send another message on the same socket to the peers that have sent
+// something. By default, the message is sent to all peers, but it can
+// be sent to only the most recently seen peer using `--inject recent`,
+// or to a single peer by giving its address. Peers that have not sent
+// anything for `--peer-timeout` seconds (60 by default) are forgotten.
+//
+// You can try it by starting several clients using `nc -u 127.0.0.1
+// 8080` in different terminals and typing something in each of them.
+//
+// Errors are reported by the task where they occur. Errors caused by
+// a single datagram, like a datagram that is not valid UTF-8 or that
+// could not be sent, are printed and the task continues, while errors
+// that leave the task unable to work, like a closed socket manager,
+// stop the task.
 
+use bytes::Bytes;
 use futures::prelude::*;
-use std::env;
+use std::fmt;
 use std::net::SocketAddr;
 use std::result::Result;
-use std::str::from_utf8;
+use std::str::{from_utf8, Utf8Error};
 use std::time::Duration;
+use tokio::join;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
 use tokio::time::interval;
-use tokio::{io, join};
-
-struct Message {
-    buf: String,
-    dest: Option<SocketAddr>,
-}
+use tokio_examples::config::Config;
+use tokio_examples::datagram::DatagramBuffer;
+use tokio_examples::socket_manager::{
+    Destination, RecvError, SendError, SocketHandle, SocketManager,
+};
 
 #[derive(Debug)]
 enum Error {
-    GenericError(String),
-    EncodingError(std::str::Utf8Error),
-    IOError(std::io::Error),
+    // Datagram that was not valid UTF-8.
+    Encoding { addr: SocketAddr, error: Utf8Error },
+
+    // Failure to receive from the socket.
+    Receive(RecvError),
+
+    // Failure to send a datagram to a destination.
+    Send { dest: SocketAddr, error: SendError },
 }
 
-impl From<std::str::Utf8Error> for Error {
-    fn from(error: std::str::Utf8Error) -> Error {
-        Error::EncodingError(error)
+impl Error {
+    // Check if the task has to stop after the error. Errors caused by
+    // a single datagram are not fatal, but nothing more can be sent or
+    // received once the socket manager is closed.
+    fn is_fatal(&self) -> bool {
+        match self {
+            Error::Encoding { .. } => false,
+            Error::Receive(error) => error.is_fatal(),
+            Error::Send { error, .. } => matches!(error, SendError::Closed),
+        }
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(error: std::io::Error) -> Error {
-        Error::IOError(error)
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encoding { addr, error } => {
+                write!(f, "datagram from {} is not valid UTF-8: {}", addr, error)
+            }
+            Error::Receive(error) => write!(f, "{}", error),
+            Error::Send { dest, error } => write!(f, "to {}: {}", dest, error),
+        }
     }
 }
 
-impl<M, A> From<mpsc::error::TrySendError<(M, A)>> for Error {
-    fn from(error: mpsc::error::TrySendError<(M, A)>) -> Error {
-        Error::GenericError(format!("{}", error))
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Encoding { error, .. } => Some(error),
+            Error::Receive(error) => Some(error),
+            Error::Send { error, .. } => Some(error),
+        }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let address = env::args().nth(1).unwrap_or("127.0.0.1:8080".to_string());
-    let socket = {
-        let addr = address.parse::<SocketAddr>()?;
-        UdpSocket::bind(&addr).await?
-    };
+// Print an error that the task can recover from, and return the other
+// errors so that the task stops.
+fn recover(task: &str, error: Error) -> Result<(), Error> {
+    if error.is_fatal() {
+        return Err(error);
+    }
+    println!("{}: {}", task, error);
+    Ok(())
+}
 
-    // Here we split the socket into the sender and receiver side. We
-    // cannot clone the sender (writer) side, so we have to handle
-    // this using an mpsc channel.
-    let (mut reader, mut writer) = socket.split();
-
-    // Create an mpsc channel to use for internal communication. We
-    // can clone the writer side of the channel (tx), so we are going
-    // to use that later.
-    let (mut tx, mut rx): (mpsc::Sender<Message>, mpsc::Receiver<Message>) = mpsc::channel(10);
-
-    // This is the transmitting task that will transmit anything that
-    // arrives on the channel. The socket address is optional, and if
-    // none is provided, the last used address will be used.
-    let transmitter_task = {
-        let mut last_address: Option<SocketAddr> = None;
-        async move {
-            while let Some(msg) = rx.recv().await {
-                let address = match msg.dest {
-                    Some(addr) => Some(addr),
-                    None => last_address,
-                };
-                last_address = address;
-                if let Some(dest) = address {
-                    let packet = format!("FYI - {}\n", msg.buf);
-                    writer.send_to(packet.as_bytes(), &dest).await?;
-                }
-            }
-            Ok::<_, io::Error>(())
-        }
-    };
+// Print how a task stopped.
+fn report(task: &str, result: Result<Result<(), Error>, tokio::task::JoinError>) {
+    match result {
+        Ok(Ok(())) => println!("{}: done", task),
+        Ok(Err(error)) => println!("{}: stopped: {}", task, error),
+        Err(error) => println!("{}: failed: {}", task, error),
+    }
+}
+
+// Make the packet for a message.
+fn packet(msg: &str) -> Bytes {
+    Bytes::from(format!("FYI - {}\n", msg))
+}
+
+// Send a message on the socket.
+async fn send(handle: &SocketHandle, msg: &str, dest: SocketAddr) -> Result<(), Error> {
+    match handle.send(packet(msg), dest).await {
+        Ok(_) => Ok(()),
+        Err(error) => Err(Error::Send { dest, error }),
+    }
+}
 
-    // This is the receiver task that handles all incoming
-    // packets. They are just relayed to the transmitter task.
+// Make the response to a received datagram.
+fn respond(data: &[u8], addr: SocketAddr) -> Result<String, Error> {
+    let text = from_utf8(data).map_err(|error| Error::Encoding { addr, error })?;
+    Ok(format!("Simon says: '{}'", text))
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind(config.bind).await?;
+    let timeout = Duration::from_secs(config.parse("peer-timeout")?.unwrap_or(60));
+    let inject = config.parse("inject")?.unwrap_or(Destination::All);
+    let manager = SocketManager::spawn(socket, DatagramBuffer::new(config.buffer_size)?)?
+        .peer_expiry(timeout);
+    println!("Listening on: {}", manager.local_addr());
+    let (handle, mut incoming) = manager.split();
+
+    // This is the receiver task that handles all incoming packets and
+    // sends back a response.
     let receiver_task = {
-        let mut tx = tx.clone();
+        let handle = handle.clone();
         async move {
-            let mut buf = vec![0; 128];
-            loop {
-                let (count, addr) = reader.recv_from(&mut buf).await?;
-                if count == 0 {
-                    break;
-                }
-                let msg = Message {
-                    buf: format!("Simon says: '{}'", from_utf8(&buf).unwrap()),
-                    dest: Some(addr),
+            while let Some(result) = incoming.next().await {
+                let result = match result {
+                    Ok((data, addr)) => match respond(&data, addr) {
+                        Ok(msg) => send(&handle, &msg, addr).await,
+                        Err(error) => Err(error),
+                    },
+                    Err(error) => Err(Error::Receive(error)),
                 };
-                if let Err(err) = tx.send(msg).await {
-                    println!("Error: {}", err);
-                    break;
+                if let Err(error) = result {
+                    recover("receiver", error)?;
                 }
             }
-            Ok::<_, io::Error>(())
+            Ok::<_, Error>(())
         }
     };
 
-    // This is a regular task that just inject a message into the
-    // queue of the transmitter task. We use it to demonstrate how to
-    // send messages on the same socket from multiple closures.
+    // This is a regular task that just inject a message on the same
+    // socket. We use it to demonstrate how to send messages on the
+    // same socket from multiple tasks, and to several peers.
     let injector_task = {
         async move {
             let mut seconds: i32 = 1;
             let mut ticks = interval(Duration::from_millis(1000));
             while let Some(_interval) = ticks.next().await {
                 seconds += 1;
-                let msg = Message {
-                    buf: format!("{} seconds passed", seconds),
-                    dest: None,
-                };
-                if let Err(err) = tx.send(msg).await {
-                    println!("Error: {}", err);
-                    break;
+                for addr in handle.expire_peers() {
+                    println!("Peer {}: expired", addr);
+                }
+                let msg = format!("{} seconds passed", seconds);
+                for (dest, result) in handle.send_to_peers(packet(&msg), inject).await {
+                    if let Err(error) = result {
+                        recover("injector", Error::Send { dest, error })?;
+                    }
                 }
             }
-            Ok::<_, io::Error>(())
+            Ok::<_, Error>(())
         }
     };
 
-    let _ = join!(
-        tokio::spawn(transmitter_task),
-        tokio::spawn(receiver_task),
-        tokio::spawn(injector_task),
-    );
+    let (receiver, injector) = join!(tokio::spawn(receiver_task), tokio::spawn(injector_task));
+    report("receiver", receiver);
+    report("injector", injector);
 
     Ok(())
 }
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::new()
+        .bind("127.0.0.1:8080")
+        .buffer_size(128)
+        .option("inject", Some("all"))
+        .option("peer-timeout", Some("60"))
+        .load()?;
+    config.runtime()?.block_on(run(config))
+}
@@ -13,36 +13,32 @@
 // permissions and limitations under the License.
 
 // Example to demonstrate how to zip two streams that are both
-// infinite and have different types for `Error`. The fibonacci stream
-// has `()` for `Error` while the interval stream has `tokio::timer::Error`.
+// infinite and have different item types. Here we zip the stream of
+// Fibonacci numbers with a stream of indexes and then throttle the
+// zipped stream so that it produces one pair every 500 milliseconds.
 //
-// We handle this by mapping the error type for each stream to a
-// common error type, which also means that we need to map the "no
-// error" type to some sort of error enumeration, but this error can
-// never occur.
+// The throttle combinator requires the stream to be `Unpin`, which
+// the Fibonacci stream is not, so we need to pin it on the heap
+// first.
 
 extern crate futures;
 
-use futures::{stream, Stream, StreamExt};
+use futures::{stream, StreamExt};
 use std::time::Duration;
-use tokio::time;
-
-// Produce a stream of Fibonacci numbers.
-//
-// Note that the error for the stream is the same as what the
-// interval timer for Tokio produces.
-fn fibonacci() -> impl Stream<Item = u64> {
-    stream::unfold((1, 1), |(curr, next)| async move {
-        Some((curr, (next, curr + next)))
-    })
-}
+use tokio::time::Instant;
+use tokio_examples::{fibonacci, RateLimit, ThrottleExt};
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let pairs = time::interval(Duration::from_millis(500)).zip(fibonacci());
-    tokio::pin!(pairs);
-    while let Some((instant, number)) = pairs.next().await {
-        println!("fire; instant={:?}, number={}", instant, number);
+    let mut pairs = Box::pin(stream::iter(1..).zip(fibonacci()))
+        .throttle(RateLimit::FixedInterval(Duration::from_millis(500)));
+    while let Some((index, number)) = pairs.next().await {
+        println!(
+            "fire; instant={:?}, index={}, number={}",
+            Instant::now(),
+            index,
+            number
+        );
     }
 
     Ok(())
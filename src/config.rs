@@ -0,0 +1,457 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Configuration of the network examples.
+//!
+//! All network examples share the same basic configuration: the
+//! address to bind to, a list of destination addresses, the size of
+//! the receive buffer, and the number of worker threads. Each example
+//! provides its own defaults, which can be overridden first by
+//! environment variables and then by command-line options:
+//!
+//! | Option               | Environment variable           |
+//! |----------------------|--------------------------------|
+//! | `--bind ADDR`        | `TOKIO_EXAMPLES_BIND`          |
+//! | `--dest ADDR`        | `TOKIO_EXAMPLES_DESTINATIONS`  |
+//! | `--buffer-size SIZE` | `TOKIO_EXAMPLES_BUFFER_SIZE`   |
+//! | `--threads COUNT`    | `TOKIO_EXAMPLES_THREADS`       |
+//!
+//! The `--dest` option can be given several times, and the
+//! environment variable holds a comma-separated list of addresses.
+//! Examples can also add their own options, which all take a value,
+//! and any arguments that are not options are collected as positional
+//! arguments.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use tokio::runtime::{self, Runtime};
+
+const ENV_PREFIX: &str = "TOKIO_EXAMPLES_";
+
+/// Error in the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// An option was given without a value.
+    MissingValue(String),
+
+    /// An option that the example does not know about was given.
+    UnknownOption(String),
+
+    /// The value of an option or environment variable was not valid.
+    InvalidValue {
+        name: String,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingValue(name) => write!(f, "missing value for {}", name),
+            ConfigError::UnknownOption(name) => write!(f, "unknown option {}", name),
+            ConfigError::InvalidValue {
+                name,
+                value,
+                reason,
+            } => write!(f, "invalid value '{}' for {}: {}", value, name, reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+fn invalid_value(name: &str, value: &str, reason: impl fmt::Display) -> ConfigError {
+    ConfigError::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+fn parse_address(name: &str, value: &str) -> Result<SocketAddr, ConfigError> {
+    let mut addrs = value
+        .to_socket_addrs()
+        .map_err(|err| invalid_value(name, value, err))?;
+    addrs
+        .next()
+        .ok_or_else(|| invalid_value(name, value, "address did not resolve"))
+}
+
+fn parse_count(name: &str, value: &str) -> Result<usize, ConfigError> {
+    match value.parse() {
+        Ok(0) => Err(invalid_value(name, value, "has to be positive")),
+        Ok(count) => Ok(count),
+        Err(err) => Err(invalid_value(name, value, err)),
+    }
+}
+
+/// Configuration of a network example.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address to bind the listening socket to.
+    pub bind: SocketAddr,
+
+    /// Addresses to send to.
+    pub destinations: Vec<SocketAddr>,
+
+    /// Size of the receive buffer.
+    pub buffer_size: usize,
+
+    /// Number of worker threads of the runtime.
+    pub threads: usize,
+
+    /// Arguments that are not options.
+    pub args: Vec<String>,
+
+    // Values of the options added by the example.
+    options: HashMap<String, Option<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    /// Create a configuration with the default values.
+    ///
+    /// By default, the socket is bound to any address with a port
+    /// picked by the operating system, there are no destinations, the
+    /// buffer size is 1024 bytes, and the number of threads is the
+    /// number of cores.
+    pub fn new() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            destinations: Vec::new(),
+            buffer_size: 1024,
+            threads: num_cpus(),
+            args: Vec::new(),
+            options: HashMap::new(),
+        }
+    }
+
+    /// Set the default bind address.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the address is not valid, since it is provided by the
+    /// example and not by the user.
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.bind = addr.parse().expect("invalid default bind address");
+        self
+    }
+
+    /// Set the default destination addresses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the addresses is not valid.
+    pub fn destinations(mut self, addrs: &[&str]) -> Self {
+        self.destinations = addrs
+            .iter()
+            .map(|addr| addr.parse().expect("invalid default destination"))
+            .collect();
+        self
+    }
+
+    /// Set the default buffer size.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Set the default number of worker threads.
+    pub fn threads(mut self, count: usize) -> Self {
+        self.threads = count;
+        self
+    }
+
+    /// Add an option specific to the example, with an optional
+    /// default value.
+    ///
+    /// The option is given as `--name VALUE` on the command line, or
+    /// using an environment variable with the name in upper case and
+    /// dashes replaced with underscores.
+    pub fn option(mut self, name: &str, default: Option<&str>) -> Self {
+        self.options
+            .insert(name.to_string(), default.map(str::to_string));
+        self
+    }
+
+    /// Get the value of an option added with `option`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name)?.as_deref()
+    }
+
+    /// Parse the value of an option added with `option`.
+    pub fn parse<T>(&self, name: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: std::str::FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|err| invalid_value(&format!("--{}", name), value, err)),
+            None => Ok(None),
+        }
+    }
+
+    /// Load the configuration from the environment and the command
+    /// line of the process.
+    pub fn load(self) -> Result<Self, ConfigError> {
+        self.load_from(env::vars(), env::args().skip(1))
+    }
+
+    /// Load the configuration from the given environment variables
+    /// and arguments.
+    pub fn load_from<V, A>(mut self, vars: V, args: A) -> Result<Self, ConfigError>
+    where
+        V: IntoIterator<Item = (String, String)>,
+        A: IntoIterator<Item = String>,
+    {
+        for (key, value) in vars {
+            if let Some(name) = key.strip_prefix(ENV_PREFIX) {
+                let name = name.to_lowercase().replace('_', "-");
+                if name == "destinations" {
+                    self.destinations = value
+                        .split(',')
+                        .map(|addr| parse_address(&key, addr.trim()))
+                        .collect::<Result<_, _>>()?;
+                } else if self.is_option(&name) {
+                    self.set(&key, &name, value)?;
+                }
+            }
+        }
+
+        let mut destinations = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                if !self.is_option(name) && name != "dest" {
+                    return Err(ConfigError::UnknownOption(arg));
+                }
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                if name == "dest" {
+                    destinations.push(parse_address(&arg, &value)?);
+                } else {
+                    self.set(&arg, name, value)?;
+                }
+            } else {
+                self.args.push(arg);
+            }
+        }
+        if !destinations.is_empty() {
+            self.destinations = destinations;
+        }
+        Ok(self)
+    }
+
+    fn is_option(&self, name: &str) -> bool {
+        match name {
+            "bind" | "buffer-size" | "threads" => true,
+            _ => self.options.contains_key(name),
+        }
+    }
+
+    // Set the option `name` from the option or environment variable
+    // `source`.
+    fn set(&mut self, source: &str, name: &str, value: String) -> Result<(), ConfigError> {
+        match name {
+            "bind" => self.bind = parse_address(source, &value)?,
+            "buffer-size" => self.buffer_size = parse_count(source, &value)?,
+            "threads" => self.threads = parse_count(source, &value)?,
+            _ => {
+                self.options.insert(name.to_string(), Some(value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a runtime with the configured number of worker threads.
+    pub fn runtime(&self) -> io::Result<Runtime> {
+        runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(self.threads)
+            .enable_all()
+            .build()
+    }
+}
+
+// Number of worker threads to use by default.
+fn num_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |count| count.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn config() -> Config {
+        Config::new()
+            .bind("127.0.0.1:6142")
+            .destinations(&["127.0.0.1:6150"])
+            .option("mode", Some("tee"))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = config().load_from(vars(&[]), args(&[])).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:6142".parse().unwrap());
+        assert_eq!(config.destinations, vec!["127.0.0.1:6150".parse().unwrap()]);
+        assert_eq!(config.buffer_size, 1024);
+        assert_eq!(config.get("mode"), Some("tee"));
+    }
+
+    #[test]
+    fn test_env_overrides_defaults() {
+        let env = vars(&[
+            ("TOKIO_EXAMPLES_BIND", "0.0.0.0:7000"),
+            (
+                "TOKIO_EXAMPLES_DESTINATIONS",
+                "127.0.0.1:7001, 127.0.0.1:7002",
+            ),
+            ("TOKIO_EXAMPLES_BUFFER_SIZE", "512"),
+            ("TOKIO_EXAMPLES_MODE", "proxy"),
+            ("OTHER_MODE", "ignored"),
+        ]);
+        let config = config().load_from(env, args(&[])).unwrap();
+        assert_eq!(config.bind, "0.0.0.0:7000".parse().unwrap());
+        assert_eq!(
+            config.destinations,
+            vec![
+                "127.0.0.1:7001".parse().unwrap(),
+                "127.0.0.1:7002".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.buffer_size, 512);
+        assert_eq!(config.get("mode"), Some("proxy"));
+    }
+
+    #[test]
+    fn test_args_override_env() {
+        let env = vars(&[
+            ("TOKIO_EXAMPLES_BIND", "0.0.0.0:7000"),
+            ("TOKIO_EXAMPLES_DESTINATIONS", "127.0.0.1:7001"),
+            ("TOKIO_EXAMPLES_BUFFER_SIZE", "512"),
+            ("TOKIO_EXAMPLES_MODE", "proxy"),
+        ]);
+        let cmdline = args(&[
+            "--bind",
+            "0.0.0.0:8000",
+            "--dest",
+            "127.0.0.1:8001",
+            "--dest",
+            "127.0.0.1:8002",
+            "--buffer-size",
+            "2048",
+            "--mode",
+            "tee",
+            "hello",
+        ]);
+        let config = config().load_from(env, cmdline).unwrap();
+        assert_eq!(config.bind, "0.0.0.0:8000".parse().unwrap());
+        assert_eq!(
+            config.destinations,
+            vec![
+                "127.0.0.1:8001".parse().unwrap(),
+                "127.0.0.1:8002".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.buffer_size, 2048);
+        assert_eq!(config.get("mode"), Some("tee"));
+        assert_eq!(config.args, vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn test_unknown_option() {
+        let result = config().load_from(vars(&[]), args(&["--colour", "red"]));
+        assert_eq!(
+            result.unwrap_err(),
+            ConfigError::UnknownOption("--colour".to_string())
+        );
+    }
+
+    #[test]
+    fn test_missing_value() {
+        let result = config().load_from(vars(&[]), args(&["--mode"]));
+        assert_eq!(
+            result.unwrap_err(),
+            ConfigError::MissingValue("--mode".to_string())
+        );
+    }
+
+    #[test]
+    fn test_malformed_addresses() {
+        for cmdline in &[
+            ["--bind", "127.0.0.1"],
+            ["--bind", "127.0.0.1:99999"],
+            ["--dest", "not an address"],
+        ] {
+            match config().load_from(vars(&[]), args(cmdline)) {
+                Err(ConfigError::InvalidValue { name, value, .. }) => {
+                    assert_eq!(name, cmdline[0]);
+                    assert_eq!(value, cmdline[1]);
+                }
+                result => panic!("expected invalid value, got {:?}", result),
+            }
+        }
+        let env = vars(&[("TOKIO_EXAMPLES_DESTINATIONS", "127.0.0.1:7001,bad")]);
+        match config().load_from(env, args(&[])) {
+            Err(ConfigError::InvalidValue { name, value, .. }) => {
+                assert_eq!(name, "TOKIO_EXAMPLES_DESTINATIONS");
+                assert_eq!(value, "bad");
+            }
+            result => panic!("expected invalid value, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_invalid_counts() {
+        for value in &["0", "-1", "many"] {
+            let result = config().load_from(vars(&[]), args(&["--buffer-size", value]));
+            assert!(matches!(result, Err(ConfigError::InvalidValue { .. })));
+        }
+    }
+
+    #[test]
+    fn test_parse_option() {
+        let config = config()
+            .option("count", None)
+            .load_from(vars(&[]), args(&["--count", "ten"]))
+            .unwrap();
+        assert!(config.parse::<u32>("count").is_err());
+        assert_eq!(config.parse::<u32>("missing").unwrap(), None);
+    }
+}
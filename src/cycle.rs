@@ -0,0 +1,200 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Stream combinator that repeats a stream.
+//!
+//! The `cycle` combinator in `futures::StreamExt` requires the stream
+//! to be cloneable so that it can restart it when it ends. Most
+//! streams are not cloneable, so the combinator here instead buffers
+//! the items on the first pass over the stream and replays them from
+//! the buffer on each following pass.
+
+use futures::stream::{Fuse, Stream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Extension trait adding the `cycle` combinator to streams.
+///
+/// Note that `futures::StreamExt` also has a `cycle` method, so if
+/// both traits are in scope you need to call this one as
+/// `CycleExt::cycle(stream)`.
+pub trait CycleExt: Stream {
+    /// Create a stream that repeats the items of this stream forever.
+    ///
+    /// The items are buffered when they are produced by the first
+    /// pass, so the item type has to be cloneable but the stream
+    /// itself does not. If the stream is empty, the cycle is empty as
+    /// well.
+    fn cycle(self) -> Cycle<Self>
+    where
+        Self: Sized + Unpin,
+        Self::Item: Clone,
+    {
+        Cycle::new(self)
+    }
+}
+
+impl<S: Stream + ?Sized> CycleExt for S {}
+
+/// Stream for the `CycleExt::cycle` method.
+#[derive(Debug)]
+pub struct Cycle<S: Stream> {
+    stream: Fuse<S>,
+    buffer: Vec<S::Item>,
+    index: usize,
+    cycles: usize,
+    max_items: Option<usize>,
+    max_cycles: Option<usize>,
+}
+
+// The buffer is only `Unpin` if the items are, but it is never
+// pinned, so the cycle is `Unpin` whenever the underlying stream is.
+impl<S: Stream + Unpin> Unpin for Cycle<S> {}
+
+impl<S> Cycle<S>
+where
+    S: Stream + Unpin,
+    S::Item: Clone,
+{
+    fn new(stream: S) -> Self {
+        Self {
+            stream: stream.fuse(),
+            buffer: Vec::new(),
+            index: 0,
+            cycles: 0,
+            max_items: None,
+            max_cycles: None,
+        }
+    }
+
+    /// Limit the number of items that are buffered.
+    ///
+    /// All items of the first pass are passed on, but only the first
+    /// `count` items are saved and replayed on the following passes.
+    pub fn max_items(mut self, count: usize) -> Self {
+        self.max_items = Some(count);
+        self
+    }
+
+    /// Limit the number of passes over the items.
+    ///
+    /// The first pass over the underlying stream counts as a pass, so
+    /// setting this to 1 just produces the items of the underlying
+    /// stream once.
+    pub fn max_cycles(mut self, count: usize) -> Self {
+        self.max_cycles = Some(count);
+        self
+    }
+
+    fn is_finished(&self) -> bool {
+        self.max_cycles.is_some_and(|max| self.cycles >= max)
+    }
+}
+
+impl<S> Stream for Cycle<S>
+where
+    S: Stream + Unpin,
+    S::Item: Clone,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_finished() {
+            return Poll::Ready(None);
+        }
+
+        // On the first pass, we read items from the underlying stream
+        // and save them in the buffer (if there is room).
+        if !self.stream.is_done() {
+            match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    if self.max_items.is_none_or(|max| self.buffer.len() < max) {
+                        self.buffer.push(item.clone());
+                    }
+                    return Poll::Ready(Some(item));
+                }
+                Poll::Ready(None) => self.cycles += 1,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        // On the following passes, we replay the items from the
+        // buffer.
+        if self.buffer.is_empty() || self.is_finished() {
+            return Poll::Ready(None);
+        }
+        let item = self.buffer[self.index].clone();
+        self.index += 1;
+        if self.index == self.buffer.len() {
+            self.index = 0;
+            self.cycles += 1;
+        }
+        Poll::Ready(Some(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream;
+    use std::marker::PhantomPinned;
+
+    // Collect the first `count` items of a stream.
+    fn take<S: Stream>(stream: S, count: usize) -> Vec<S::Item> {
+        block_on(stream.take(count).collect())
+    }
+
+    #[test]
+    fn replay_in_order() {
+        let cycle = CycleExt::cycle(stream::iter(vec![1, 2, 3]));
+        assert_eq!(take(cycle, 8), vec![1, 2, 3, 1, 2, 3, 1, 2]);
+    }
+
+    #[test]
+    fn truncate_with_max_items() {
+        let cycle = CycleExt::cycle(stream::iter(vec![1, 2, 3])).max_items(2);
+        assert_eq!(take(cycle, 7), vec![1, 2, 3, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn limit_with_max_cycles() {
+        let cycle = CycleExt::cycle(stream::iter(vec![1, 2, 3])).max_cycles(0);
+        assert_eq!(take(cycle, 10), Vec::<i32>::new());
+
+        let cycle = CycleExt::cycle(stream::iter(vec![1, 2, 3])).max_cycles(1);
+        assert_eq!(take(cycle, 10), vec![1, 2, 3]);
+
+        let cycle = CycleExt::cycle(stream::iter(vec![1, 2])).max_cycles(3);
+        assert_eq!(take(cycle, 10), vec![1, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn empty_stream_ends() {
+        let mut cycle = CycleExt::cycle(stream::iter(Vec::<i32>::new()));
+        assert_eq!(block_on(cycle.next()), None);
+        assert_eq!(block_on(cycle.next()), None);
+
+        // Nothing is buffered, so the cycle ends after the first pass.
+        let cycle = CycleExt::cycle(stream::iter(vec![1, 2])).max_items(0);
+        assert_eq!(take(cycle, 10), vec![1, 2]);
+    }
+
+    #[test]
+    fn cycle_items_that_are_not_unpin() {
+        let items = vec![PhantomPinned, PhantomPinned];
+        let cycle = CycleExt::cycle(stream::iter(items));
+        assert_eq!(take(cycle, 5).len(), 5);
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Receiving datagrams without silently truncating them.
+//!
+//! When a datagram is larger than the buffer passed to `recv_from`,
+//! the rest of the datagram is discarded without any indication. To
+//! detect this, a `DatagramBuffer` uses a buffer that is one byte
+//! larger than the maximum datagram size: if the extra byte is used,
+//! the datagram was too large.
+//!
+//! `SizeStats` collects the sizes of received datagrams, which is
+//! useful to pick a suitable maximum size for the traffic.
+
+use std::error::Error;
+use std::fmt;
+
+/// Largest datagram size that can be configured, which is the largest
+/// payload of a UDP datagram over IPv4: 65535 bytes minus the 20 byte
+/// IP header and the 8 byte UDP header.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Error returned for a maximum datagram size that is out of range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeError {
+    pub size: usize,
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "datagram size {} is not between 1 and {}",
+            self.size, MAX_DATAGRAM_SIZE
+        )
+    }
+}
+
+impl Error for SizeError {}
+
+/// Received datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Datagram<'a> {
+    /// Contents of the datagram, at most the maximum datagram size.
+    pub data: &'a [u8],
+
+    /// If the datagram was larger than the maximum datagram size, in
+    /// which case `data` only holds the beginning of it.
+    pub truncated: bool,
+}
+
+/// Buffer for receiving datagrams that detects truncation.
+#[derive(Debug, Clone)]
+pub struct DatagramBuffer {
+    buf: Vec<u8>,
+    max_size: usize,
+}
+
+impl DatagramBuffer {
+    /// Create a buffer for datagrams of at most `max_size` bytes.
+    pub fn new(max_size: usize) -> Result<Self, SizeError> {
+        if max_size == 0 || max_size > MAX_DATAGRAM_SIZE {
+            return Err(SizeError { size: max_size });
+        }
+        Ok(Self {
+            buf: vec![0; max_size + 1],
+            max_size,
+        })
+    }
+
+    /// Maximum datagram size.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Buffer to receive into.
+    ///
+    /// The buffer is one byte larger than the maximum datagram size.
+    pub fn recv_buf(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+
+    /// Get the datagram after receiving `bytes` bytes into the buffer.
+    pub fn datagram(&self, bytes: usize) -> Datagram<'_> {
+        Datagram {
+            data: &self.buf[0..bytes.min(self.max_size)],
+            truncated: bytes > self.max_size,
+        }
+    }
+}
+
+// Number of buckets, one for each power of two up to the maximum
+// datagram size.
+const BUCKETS: usize = 17;
+
+/// Statistics on the sizes of received datagrams.
+#[derive(Debug, Clone, Default)]
+pub struct SizeStats {
+    count: u64,
+    truncated: u64,
+    bytes: u64,
+    min: usize,
+    max: usize,
+    buckets: [u64; BUCKETS],
+}
+
+impl SizeStats {
+    /// Create empty statistics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a received datagram.
+    ///
+    /// The size of a truncated datagram is only known to be larger
+    /// than the maximum size, so it is counted as the maximum size.
+    pub fn record(&mut self, datagram: &Datagram<'_>) {
+        let size = datagram.data.len();
+        if self.count == 0 || size < self.min {
+            self.min = size;
+        }
+        self.max = self.max.max(size);
+        self.count += 1;
+        self.bytes += size as u64;
+        if datagram.truncated {
+            self.truncated += 1;
+        }
+        self.buckets[bucket(size)] += 1;
+    }
+
+    /// Number of datagrams received.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Number of datagrams that were truncated.
+    pub fn truncated(&self) -> u64 {
+        self.truncated
+    }
+}
+
+// Index of the smallest power of two that is at least `size`.
+fn bucket(size: usize) -> usize {
+    size.next_power_of_two().trailing_zeros() as usize
+}
+
+impl fmt::Display for SizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "no datagrams");
+        }
+        write!(
+            f,
+            "{} datagrams ({} truncated), size min={} max={} mean={}",
+            self.count,
+            self.truncated,
+            self.min,
+            self.max,
+            self.bytes / self.count
+        )?;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            if count > 0 {
+                write!(f, "\n  <= {:5} bytes: {}", 1usize << index, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    fn datagram(data: &[u8], truncated: bool) -> Datagram<'_> {
+        Datagram { data, truncated }
+    }
+
+    #[test]
+    fn check_size() {
+        assert_eq!(DatagramBuffer::new(0).unwrap_err(), SizeError { size: 0 });
+        assert!(DatagramBuffer::new(MAX_DATAGRAM_SIZE).is_ok());
+        let err = DatagramBuffer::new(MAX_DATAGRAM_SIZE + 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "datagram size 65508 is not between 1 and 65507"
+        );
+    }
+
+    #[tokio::test]
+    async fn detect_truncation() {
+        let mut receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let mut sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = DatagramBuffer::new(8).unwrap();
+
+        for data in &[&b"12345678"[..], b"123456789", b""] {
+            sender.send_to(data, &addr).await.unwrap();
+            let (bytes, _) = receiver.recv_from(buf.recv_buf()).await.unwrap();
+            let received = buf.datagram(bytes);
+            assert_eq!(received.truncated, data.len() > 8);
+            assert_eq!(received.data, &data[..data.len().min(8)]);
+        }
+    }
+
+    #[test]
+    fn bucket_sizes() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(2), 1);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(1024), 10);
+        assert_eq!(bucket(1025), 11);
+        assert_eq!(bucket(MAX_DATAGRAM_SIZE), BUCKETS - 1);
+    }
+
+    #[test]
+    fn show_stats() {
+        let mut stats = SizeStats::new();
+        assert_eq!(stats.to_string(), "no datagrams");
+
+        stats.record(&datagram(&[0; 3], false));
+        stats.record(&datagram(&[0; 4], false));
+        stats.record(&datagram(&[0; 100], true));
+        assert_eq!(stats.count(), 3);
+        assert_eq!(stats.truncated(), 1);
+        assert_eq!(
+            stats.to_string(),
+            "3 datagrams (1 truncated), size min=3 max=100 mean=35\n  \
+             <=     4 bytes: 2\n  \
+             <=   128 bytes: 1"
+        );
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Framing of text messages sent over a byte stream.
+//!
+//! TCP is a byte stream, so the messages written by a sender can be
+//! split or merged arbitrarily when they are read by the receiver. To
+//! get back the messages that were sent, each message has to be framed
+//! so that the receiver can find where it ends. The `MessageCodec`
+//! supports two kinds of framing:
+//!
+//! - Length-delimited framing, where each message is prefixed with its
+//!   length as a 32-bit big-endian integer.
+//!
+//! - Newline-delimited framing, where each message ends with a
+//!   newline. This is easy to use with `nc`, but the messages cannot
+//!   contain newlines, and encoding such a message is an
+//!   `InvalidInput` error.
+//!
+//! With both framings, messages are limited to `MAX_MESSAGE_LENGTH`
+//! bytes by default, so that a peer cannot make the receiver buffer
+//! an unbounded amount of data. A longer message is reported as an
+//! `InvalidData` error.
+
+use bytes::{Bytes, BytesMut};
+use std::io;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{
+    Decoder, Encoder, Framed, LengthDelimitedCodec, LinesCodec, LinesCodecError,
+};
+
+/// Default maximum length of a message, which is the same as the
+/// default maximum frame length of `LengthDelimitedCodec`.
+pub const MAX_MESSAGE_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Kind of framing used for messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Each message is prefixed with its length.
+    LengthDelimited,
+
+    /// Each message ends with a newline.
+    Newline,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "length" => Ok(Framing::LengthDelimited),
+            "newline" => Ok(Framing::Newline),
+            _ => Err(format!("unknown framing '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Codec {
+    LengthDelimited(LengthDelimitedCodec),
+    Newline(LinesCodec),
+}
+
+/// Codec for text messages using either of the framings.
+#[derive(Debug)]
+pub struct MessageCodec {
+    codec: Codec,
+    max_length: usize,
+}
+
+impl MessageCodec {
+    /// Create a new codec using `framing` and the default maximum
+    /// message length.
+    pub fn new(framing: Framing) -> Self {
+        Self::with_max_length(framing, MAX_MESSAGE_LENGTH)
+    }
+
+    /// Create a new codec using `framing` for messages of at most
+    /// `max_length` bytes.
+    pub fn with_max_length(framing: Framing, max_length: usize) -> Self {
+        let codec = match framing {
+            Framing::LengthDelimited => Codec::LengthDelimited(
+                LengthDelimitedCodec::builder()
+                    .max_frame_length(max_length)
+                    .new_codec(),
+            ),
+            Framing::Newline => Codec::Newline(LinesCodec::new_with_max_length(max_length)),
+        };
+        Self { codec, max_length }
+    }
+
+    fn too_long(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message is longer than {} bytes", self.max_length),
+        )
+    }
+
+    // The only data error from the length-delimited codec is for a
+    // frame that is too long.
+    fn length_error(&self, error: io::Error) -> io::Error {
+        match error.kind() {
+            io::ErrorKind::InvalidData => self.too_long(),
+            _ => error,
+        }
+    }
+
+    // Convert errors from the lines codec to I/O errors, so that both
+    // framings have the same error type.
+    fn lines_error(&self, error: LinesCodecError) -> io::Error {
+        match error {
+            LinesCodecError::Io(err) => err,
+            LinesCodecError::MaxLineLengthExceeded => self.too_long(),
+        }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<String>> {
+        match &mut self.codec {
+            Codec::LengthDelimited(codec) => {
+                let result = codec.decode(src);
+                match result.map_err(|err| self.length_error(err))? {
+                    Some(frame) => String::from_utf8(frame.to_vec())
+                        .map(Some)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+                    None => Ok(None),
+                }
+            }
+            Codec::Newline(codec) => {
+                let result = codec.decode(src);
+                result.map_err(|err| self.lines_error(err))
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<String>> {
+        match &mut self.codec {
+            Codec::LengthDelimited(_) => match self.decode(src)? {
+                Some(message) => Ok(Some(message)),
+                None if src.is_empty() => Ok(None),
+                None => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "bytes remaining on stream",
+                )),
+            },
+            Codec::Newline(codec) => {
+                let result = codec.decode_eof(src);
+                result.map_err(|err| self.lines_error(err))
+            }
+        }
+    }
+}
+
+impl Encoder for MessageCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn encode(&mut self, message: String, dst: &mut BytesMut) -> io::Result<()> {
+        match &mut self.codec {
+            Codec::LengthDelimited(codec) => codec.encode(Bytes::from(message), dst),
+            Codec::Newline(_) if message.contains('\n') => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message contains a newline",
+            )),
+            Codec::Newline(codec) => {
+                let result = codec.encode(message, dst);
+                result.map_err(|err| self.lines_error(err))
+            }
+        }
+    }
+}
+
+/// Frame a byte stream, for example a `TcpStream`, so that it can be
+/// used to send and receive messages using `framing`.
+pub fn framed<T>(io: T, framing: Framing) -> Framed<T, MessageCodec>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    Framed::new(io, MessageCodec::new(framing))
+}
+
+/// Frame a byte stream like `framed`, but limit the messages to
+/// `max_length` bytes.
+pub fn framed_with_max_length<T>(
+    io: T,
+    framing: Framing,
+    max_length: usize,
+) -> Framed<T, MessageCodec>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    Framed::new(io, MessageCodec::with_max_length(framing, max_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<String> {
+        vec![
+            "first".to_string(),
+            String::new(),
+            "third message".to_string(),
+        ]
+    }
+
+    fn encode(codec: &mut MessageCodec, messages: &[String]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for message in messages {
+            codec.encode(message.clone(), &mut buf).unwrap();
+        }
+        buf
+    }
+
+    // Decode everything in `buf`, which can hold several messages.
+    fn decode_all(codec: &mut MessageCodec, buf: &mut BytesMut) -> Vec<String> {
+        let mut result = Vec::new();
+        while let Some(message) = codec.decode_eof(buf).unwrap() {
+            result.push(message);
+        }
+        result
+    }
+
+    #[test]
+    fn round_trip() {
+        for &framing in &[Framing::LengthDelimited, Framing::Newline] {
+            let mut codec = MessageCodec::new(framing);
+            let mut buf = encode(&mut codec, &messages());
+            assert_eq!(decode_all(&mut codec, &mut buf), messages());
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn split_messages() {
+        for &framing in &[Framing::LengthDelimited, Framing::Newline] {
+            let mut codec = MessageCodec::new(framing);
+            let data = encode(&mut codec, &messages());
+
+            // Feed the data one byte at a time, as if each byte was a
+            // separate read.
+            let mut buf = BytesMut::new();
+            let mut result = Vec::new();
+            for &byte in data.iter() {
+                buf.extend_from_slice(&[byte]);
+                if let Some(message) = codec.decode(&mut buf).unwrap() {
+                    result.push(message);
+                }
+            }
+            assert_eq!(result, messages());
+        }
+    }
+
+    #[test]
+    fn reject_long_messages() {
+        for &framing in &[Framing::LengthDelimited, Framing::Newline] {
+            let mut codec = MessageCodec::new(framing);
+            let mut buf = encode(&mut codec, &["too long".to_string()]);
+            let mut codec = MessageCodec::with_max_length(framing, 4);
+            let err = codec.decode(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(err.to_string(), "message is longer than 4 bytes");
+        }
+    }
+
+    #[test]
+    fn reject_newline_in_message() {
+        let mut codec = MessageCodec::new(Framing::Newline);
+        let mut buf = BytesMut::new();
+        let err = codec
+            .encode("two\nlines".to_string(), &mut buf)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+
+        // Length-delimited messages can contain newlines.
+        let mut codec = MessageCodec::new(Framing::LengthDelimited);
+        let mut buf = encode(&mut codec, &["two\nlines".to_string()]);
+        assert_eq!(decode_all(&mut codec, &mut buf), vec!["two\nlines"]);
+    }
+
+    #[test]
+    fn truncated_message() {
+        let mut codec = MessageCodec::new(Framing::LengthDelimited);
+        let mut buf = encode(&mut codec, &messages());
+        buf.truncate(buf.len() - 1);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), Some("first".into()));
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), Some(String::new()));
+        let err = codec.decode_eof(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,399 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Simulated network impairments for datagrams.
+//!
+//! An `Impairment` describes how bad the simulated network is: the
+//! probability that a datagram is dropped, duplicated, corrupted, or
+//! reordered, and how much it is delayed. An `Impairer` applies it to
+//! a sequence of datagrams and decides when, if at all, each copy of a
+//! datagram should be sent.
+//!
+//! All decisions are taken using a seeded `Random`, so the same seed
+//! and the same sequence of datagrams give the same impairments. The
+//! impairer does not send anything itself, which is left to the
+//! caller.
+
+use crate::random::Random;
+use bytes::Bytes;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Probability, between 0 and 1.
+///
+/// It can be parsed either as a number, like "0.05", or as a
+/// percentage, like "5%".
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Probability(f64);
+
+impl Probability {
+    /// Create a probability.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` is not between 0 and 1.
+    pub fn new(p: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&p),
+            "probability must be between 0 and 1"
+        );
+        Self(p)
+    }
+
+    /// Probability as a number between 0 and 1.
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+impl FromStr for Probability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, scale) = match s.strip_suffix('%') {
+            Some(number) => (number, 100.0),
+            None => (s, 1.0),
+        };
+        let p = number
+            .trim()
+            .parse::<f64>()
+            .map_err(|err| err.to_string())?
+            / scale;
+        if (0.0..=1.0).contains(&p) {
+            Ok(Self(p))
+        } else {
+            Err(format!("probability '{}' is not between 0 and 1", s))
+        }
+    }
+}
+
+impl fmt::Display for Probability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}%", self.0 * 100.0)
+    }
+}
+
+/// Impairments to apply to datagrams.
+#[derive(Debug, Clone)]
+pub struct Impairment {
+    drop: Probability,
+    duplicate: Probability,
+    corrupt: Probability,
+    reorder: Probability,
+    delay: Duration,
+    jitter: Duration,
+    reorder_delay: Duration,
+}
+
+impl Impairment {
+    /// Create an impairment that passes all datagrams unchanged and
+    /// without delay.
+    ///
+    /// The reorder delay is 50 milliseconds by default.
+    pub fn new() -> Self {
+        Self {
+            drop: Probability::default(),
+            duplicate: Probability::default(),
+            corrupt: Probability::default(),
+            reorder: Probability::default(),
+            delay: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            reorder_delay: Duration::from_millis(50),
+        }
+    }
+
+    /// Set the probability that a datagram is dropped.
+    pub fn drop(mut self, p: Probability) -> Self {
+        self.drop = p;
+        self
+    }
+
+    /// Set the probability that a datagram is sent twice.
+    pub fn duplicate(mut self, p: Probability) -> Self {
+        self.duplicate = p;
+        self
+    }
+
+    /// Set the probability that a bit is flipped in a datagram.
+    pub fn corrupt(mut self, p: Probability) -> Self {
+        self.corrupt = p;
+        self
+    }
+
+    /// Set the probability that a datagram is held back for the
+    /// reorder delay, so that datagrams after it overtake it.
+    pub fn reorder(mut self, p: Probability) -> Self {
+        self.reorder = p;
+        self
+    }
+
+    /// Set the delay of all datagrams.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Set the jitter, which is a random extra delay between zero and
+    /// `jitter` added to each datagram.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Set the extra delay of datagrams that are reordered.
+    pub fn reorder_delay(mut self, delay: Duration) -> Self {
+        self.reorder_delay = delay;
+        self
+    }
+}
+
+impl Default for Impairment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Impairment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drop={} duplicate={} corrupt={} reorder={} delay={:?} jitter={:?} reorder delay={:?}",
+            self.drop,
+            self.duplicate,
+            self.corrupt,
+            self.reorder,
+            self.delay,
+            self.jitter,
+            self.reorder_delay
+        )
+    }
+}
+
+/// Copy of a datagram to send after a delay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scheduled {
+    /// Time to wait before sending the copy.
+    pub delay: Duration,
+
+    /// Contents of the copy, which might be corrupted.
+    pub data: Bytes,
+}
+
+/// Statistics on the impairments applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImpairmentStats {
+    /// Number of datagrams passed to the impairer.
+    pub received: u64,
+
+    /// Number of datagrams dropped.
+    pub dropped: u64,
+
+    /// Number of datagrams sent twice.
+    pub duplicated: u64,
+
+    /// Number of copies with a flipped bit.
+    pub corrupted: u64,
+
+    /// Number of copies held back for the reorder delay.
+    pub reordered: u64,
+}
+
+impl fmt::Display for ImpairmentStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received={} dropped={} duplicated={} corrupted={} reordered={}",
+            self.received, self.dropped, self.duplicated, self.corrupted, self.reordered
+        )
+    }
+}
+
+/// Applies an impairment to a sequence of datagrams.
+#[derive(Debug, Clone)]
+pub struct Impairer {
+    impairment: Impairment,
+    random: Random,
+    stats: ImpairmentStats,
+}
+
+impl Impairer {
+    /// Create an impairer taking its decisions using a generator
+    /// seeded with `seed`.
+    pub fn new(impairment: Impairment, seed: u64) -> Self {
+        Self {
+            impairment,
+            random: Random::new(seed),
+            stats: ImpairmentStats::default(),
+        }
+    }
+
+    /// Statistics on the impairments applied so far.
+    pub fn stats(&self) -> ImpairmentStats {
+        self.stats
+    }
+
+    /// Decide what to do with a datagram.
+    ///
+    /// Returns the copies of the datagram to send, which is none if it
+    /// is dropped and two if it is duplicated, each with the delay
+    /// after which it should be sent.
+    pub fn impair(&mut self, data: &[u8]) -> Vec<Scheduled> {
+        self.stats.received += 1;
+        if self.random.chance(self.impairment.drop.value()) {
+            self.stats.dropped += 1;
+            return vec![];
+        }
+        let copies = if self.random.chance(self.impairment.duplicate.value()) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| Scheduled {
+                delay: self.delay(),
+                data: self.corrupt(data),
+            })
+            .collect()
+    }
+
+    fn delay(&mut self) -> Duration {
+        let mut delay = self.impairment.delay;
+        delay += self.impairment.jitter.mul_f64(self.random.next_f64());
+        if self.random.chance(self.impairment.reorder.value()) {
+            self.stats.reordered += 1;
+            delay += self.impairment.reorder_delay;
+        }
+        delay
+    }
+
+    fn corrupt(&mut self, data: &[u8]) -> Bytes {
+        if data.is_empty() || !self.random.chance(self.impairment.corrupt.value()) {
+            return Bytes::copy_from_slice(data);
+        }
+        self.stats.corrupted += 1;
+        let mut data = data.to_vec();
+        let bit = self.random.below(data.len() as u64 * 8) as usize;
+        data[bit / 8] ^= 1 << (bit % 8);
+        Bytes::from(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagrams() -> Vec<Vec<u8>> {
+        (0..100)
+            .map(|index| format!("datagram {}", index).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_impairments() {
+        let impairment = Impairment::new()
+            .drop(Probability::new(0.2))
+            .duplicate(Probability::new(0.2))
+            .corrupt(Probability::new(0.2))
+            .reorder(Probability::new(0.2))
+            .jitter(Duration::from_millis(10));
+        let run = |seed| {
+            let mut impairer = Impairer::new(impairment.clone(), seed);
+            let copies: Vec<Vec<Scheduled>> = datagrams()
+                .iter()
+                .map(|data| impairer.impair(data))
+                .collect();
+            (copies, impairer.stats())
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn drop_all_or_nothing() {
+        let mut impairer = Impairer::new(Impairment::new().drop(Probability::new(1.0)), 42);
+        for data in datagrams() {
+            assert_eq!(impairer.impair(&data), vec![]);
+        }
+        assert_eq!(impairer.stats().dropped, 100);
+
+        let mut impairer = Impairer::new(Impairment::new().drop(Probability::new(0.0)), 42);
+        for data in datagrams() {
+            let expected = Scheduled {
+                delay: Duration::from_millis(0),
+                data: Bytes::from(data.clone()),
+            };
+            assert_eq!(impairer.impair(&data), vec![expected]);
+        }
+        assert_eq!(impairer.stats().dropped, 0);
+        assert_eq!(impairer.stats().received, 100);
+    }
+
+    #[test]
+    fn duplicate_gives_two_copies() {
+        let impairment = Impairment::new()
+            .duplicate(Probability::new(1.0))
+            .delay(Duration::from_millis(5));
+        let mut impairer = Impairer::new(impairment, 42);
+        let copies = impairer.impair(b"hello");
+        let expected = Scheduled {
+            delay: Duration::from_millis(5),
+            data: Bytes::from_static(b"hello"),
+        };
+        assert_eq!(copies, vec![expected.clone(), expected]);
+        assert_eq!(impairer.stats().duplicated, 1);
+    }
+
+    #[test]
+    fn corrupt_flips_one_bit() {
+        let mut impairer = Impairer::new(Impairment::new().corrupt(Probability::new(1.0)), 42);
+        for data in datagrams() {
+            let copies = impairer.impair(&data);
+            assert_eq!(copies.len(), 1);
+            let flipped: u32 = data
+                .iter()
+                .zip(copies[0].data.iter())
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum();
+            assert_eq!(copies[0].data.len(), data.len());
+            assert_eq!(flipped, 1);
+        }
+        assert_eq!(impairer.stats().corrupted, 100);
+
+        // There is no bit to flip in an empty datagram.
+        assert_eq!(impairer.impair(b"")[0].data, Bytes::new());
+    }
+
+    #[test]
+    fn reorder_adds_reorder_delay() {
+        let impairment = Impairment::new()
+            .reorder(Probability::new(1.0))
+            .delay(Duration::from_millis(5))
+            .reorder_delay(Duration::from_millis(20));
+        let mut impairer = Impairer::new(impairment, 42);
+        let copies = impairer.impair(b"hello");
+        assert_eq!(copies[0].delay, Duration::from_millis(25));
+        assert_eq!(impairer.stats().reordered, 1);
+    }
+
+    #[test]
+    fn parse_probability() {
+        assert_eq!("5%".parse(), Ok(Probability::new(0.05)));
+        assert_eq!("0.25".parse(), Ok(Probability::new(0.25)));
+        assert!("1.5".parse::<Probability>().is_err());
+        assert!("150%".parse::<Probability>().is_err());
+        assert!("abc".parse::<Probability>().is_err());
+    }
+}
@@ -1,10 +1,37 @@
-use futures::{stream, Stream};
+use futures::Stream;
+
+pub mod config;
+pub mod cycle;
+pub mod datagram;
+pub mod framing;
+pub mod impairment;
+pub mod multicast;
+pub mod proxy;
+pub mod queue;
+pub mod random;
+pub mod relay;
+pub mod reliable;
+pub mod routing;
+pub mod sequences;
+pub mod session;
+pub mod shared_cycle;
+pub mod shutdown;
+pub mod socket_manager;
+pub mod task_pool;
+pub mod throttle;
+pub mod versioned;
+
+pub use cycle::{Cycle, CycleExt};
+pub use shared_cycle::SharedCycleStream;
+pub use shutdown::Shutdown;
+pub use task_pool::{ResultOrder, TaskError, TaskPool};
+pub use throttle::{MissedTickPolicy, RateLimit, Throttle, ThrottleExt};
+pub use versioned::{Versioned, VersionedCell};
 
 /// Generate a stream of Fibonacci numbers
 ///
-///
+/// This is the same as `sequences::fibonacci` but with the item type
+/// fixed to `u64`.
 pub fn fibonacci() -> impl Stream<Item = u64> {
-    stream::unfold((1, 1), |(curr, next)| async move {
-        Some((curr, (next, curr + next)))
-    })
+    sequences::fibonacci()
 }
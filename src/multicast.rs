@@ -0,0 +1,243 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! IP multicast groups.
+//!
+//! A `Multicast` describes a group address together with the options
+//! used when sending to and receiving from the group. It can create
+//! sockets for publishing to the group and subscriptions that join the
+//! group, for both IPv4 and IPv6.
+//!
+//! Several subscribers on the same host can join the same group,
+//! since the sockets are created with `SO_REUSEADDR`, and with
+//! `SO_REUSEPORT` on the platforms that have it, which BSD and macOS
+//! need to let several sockets bind the same port. With loopback
+//! enabled, which is the default, a publisher and its subscribers can
+//! run on the same host:
+//!
+//! ```ignore
+//! let group = Multicast::new("239.255.0.1:6160".parse()?);
+//! let mut subscription = group.subscribe()?;
+//! group.publisher()?.send_to(b"hello", &group.addr()).await?;
+//! let (bytes, addr) = subscription.recv_from(&mut buf).await?;
+//! ```
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+/// Multicast group and the options used with it.
+#[derive(Debug, Clone)]
+pub struct Multicast {
+    group: SocketAddr,
+    ttl: u32,
+    loopback: bool,
+    interface_v4: Ipv4Addr,
+    interface_v6: u32,
+}
+
+impl Multicast {
+    /// Create a multicast group for `group`, which is the group address
+    /// together with the port to send to and receive on.
+    ///
+    /// By default, the TTL (or hop limit for IPv6) is 1, so datagrams
+    /// do not leave the local network, loopback is enabled, and the
+    /// interface is picked by the operating system.
+    pub fn new(group: SocketAddr) -> Self {
+        Self {
+            group,
+            ttl: 1,
+            loopback: true,
+            interface_v4: Ipv4Addr::UNSPECIFIED,
+            interface_v6: 0,
+        }
+    }
+
+    /// Set the TTL of datagrams sent to the group. For IPv6 this is the
+    /// hop limit.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set if datagrams sent to the group should be delivered to
+    /// subscribers on the same host.
+    pub fn loopback(mut self, loopback: bool) -> Self {
+        self.loopback = loopback;
+        self
+    }
+
+    /// Set the address of the interface to use for an IPv4 group.
+    pub fn interface_v4(mut self, interface: Ipv4Addr) -> Self {
+        self.interface_v4 = interface;
+        self
+    }
+
+    /// Set the index of the interface to use for an IPv6 group.
+    pub fn interface_v6(mut self, index: u32) -> Self {
+        self.interface_v6 = index;
+        self
+    }
+
+    /// Address of the group, including the port.
+    pub fn addr(&self) -> SocketAddr {
+        self.group
+    }
+
+    fn socket(&self) -> io::Result<Socket> {
+        if !self.group.ip().is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a multicast address", self.group.ip()),
+            ));
+        }
+        let domain = match self.group {
+            SocketAddr::V4(_) => Domain::IPV4,
+            SocketAddr::V6(_) => Domain::IPV6,
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_nonblocking(true)?;
+        match self.group.ip() {
+            IpAddr::V4(_) => {
+                socket.set_multicast_ttl_v4(self.ttl)?;
+                socket.set_multicast_loop_v4(self.loopback)?;
+                socket.set_multicast_if_v4(&self.interface_v4)?;
+            }
+            IpAddr::V6(_) => {
+                socket.set_only_v6(true)?;
+                socket.set_multicast_hops_v6(self.ttl)?;
+                socket.set_multicast_loop_v6(self.loopback)?;
+                socket.set_multicast_if_v6(self.interface_v6)?;
+            }
+        }
+        Ok(socket)
+    }
+
+    /// Create a socket for publishing datagrams to the group.
+    ///
+    /// The socket is bound to a port picked by the operating system,
+    /// so it is not a member of the group itself.
+    pub fn publisher(&self) -> io::Result<UdpSocket> {
+        let socket = self.socket()?;
+        let any: SocketAddr = match self.group {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        socket.bind(&SockAddr::from(any))?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Join the group and return a subscription receiving the
+    /// datagrams sent to it.
+    pub fn subscribe(&self) -> io::Result<Subscription> {
+        let socket = self.socket()?;
+        socket.set_reuse_address(true)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+        let any: SocketAddr = match self.group {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, self.group.port()).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, self.group.port()).into(),
+        };
+        socket.bind(&SockAddr::from(any))?;
+        match self.group.ip() {
+            IpAddr::V4(addr) => socket.join_multicast_v4(&addr, &self.interface_v4)?,
+            IpAddr::V6(addr) => socket.join_multicast_v6(&addr, self.interface_v6)?,
+        }
+        Ok(Subscription {
+            socket: UdpSocket::from_std(socket.into())?,
+            group: self.clone(),
+        })
+    }
+}
+
+/// Membership of a multicast group.
+#[derive(Debug)]
+pub struct Subscription {
+    socket: UdpSocket,
+    group: Multicast,
+}
+
+impl Subscription {
+    /// Local address of the subscription, which has the port of the
+    /// group.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Receive a datagram sent to the group.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    /// Leave the group.
+    ///
+    /// The group is also left when the subscription is dropped, since
+    /// the socket is closed, but this reports any errors.
+    pub fn leave(self) -> io::Result<()> {
+        match self.group.group.ip() {
+            IpAddr::V4(addr) => self
+                .socket
+                .leave_multicast_v4(addr, self.group.interface_v4),
+            IpAddr::V6(addr) => self
+                .socket
+                .leave_multicast_v6(&addr, self.group.interface_v6),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[test]
+    fn reject_unicast_address() {
+        let group = Multicast::new("127.0.0.1:0".parse().unwrap());
+        let err = group.subscribe().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(group.publisher().is_err());
+    }
+
+    fn group(port: u16) -> Multicast {
+        let addr = SocketAddr::from(([239, 255, 0, 169], port));
+        Multicast::new(addr).interface_v4(Ipv4Addr::LOCALHOST)
+    }
+
+    // The group is joined on the loopback interface, so this does not
+    // need a network, only a loopback interface supporting multicast.
+    #[tokio::test]
+    async fn publish_and_receive() {
+        // The first subscription gets a free port, which the second
+        // subscription then binds as well.
+        let mut first = group(0).subscribe().unwrap();
+        let group = group(first.local_addr().unwrap().port());
+        let mut second = group.subscribe().unwrap();
+        assert_eq!(second.local_addr().unwrap().port(), group.addr().port());
+        let mut publisher = group.publisher().unwrap();
+        publisher.send_to(b"hello", &group.addr()).await.unwrap();
+
+        let publisher_port = publisher.local_addr().unwrap().port();
+        for subscription in &mut [&mut first, &mut second] {
+            let mut buf = [0; 16];
+            let received = timeout(Duration::from_secs(5), subscription.recv_from(&mut buf));
+            let (bytes, addr) = received.await.expect("no datagram received").unwrap();
+            assert_eq!(&buf[..bytes], b"hello");
+            assert_eq!(addr.port(), publisher_port);
+        }
+        first.leave().unwrap();
+        second.leave().unwrap();
+    }
+}
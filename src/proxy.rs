@@ -0,0 +1,259 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Bidirectional proxying between two TCP connections.
+//!
+//! Both connections are split into read and write halves, and data is
+//! copied in each direction until the reading side is closed. When one
+//! side closes its write direction, the proxy closes the write
+//! direction of the other side, but keeps copying in the opposite
+//! direction. This means that a client can send a request, close its
+//! write direction, and still get the full response.
+//!
+//! If no data is copied in either direction for the idle timeout, both
+//! connections are closed.
+
+use futures::future::{self, Either};
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_until, Instant};
+
+/// Number of bytes copied by a proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProxyStats {
+    /// Bytes copied from the client to the upstream server.
+    pub sent: u64,
+
+    /// Bytes copied from the upstream server to the client.
+    pub received: u64,
+}
+
+/// Proxy copying data in both directions between two connections.
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    buffer_size: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Time of the last activity in either direction. Both directions are
+// copied by the same task, but the proxy can be spawned on a threaded
+// runtime, so this has to be `Sync` and cannot be a `Cell`.
+struct Activity {
+    last: Mutex<Instant>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.last.lock().unwrap()
+    }
+}
+
+impl Proxy {
+    /// Create a proxy with a buffer size of 4096 bytes and no idle
+    /// timeout.
+    pub fn new() -> Self {
+        Self {
+            buffer_size: 4096,
+            idle_timeout: None,
+        }
+    }
+
+    /// Set the size of the buffer used for each direction.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Close the connections if no data is copied in either direction
+    /// for `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Copy data between `client` and `upstream` until both directions
+    /// are closed.
+    ///
+    /// If the idle timeout expires, an error with kind `TimedOut` is
+    /// returned.
+    pub async fn run(
+        &self,
+        client: &mut TcpStream,
+        upstream: &mut TcpStream,
+    ) -> io::Result<ProxyStats> {
+        let activity = Activity::new();
+        let (client_read, client_write) = client.split();
+        let (upstream_read, upstream_write) = upstream.split();
+        let copying = future::try_join(
+            copy(client_read, upstream_write, &activity, self.buffer_size),
+            copy(upstream_read, client_write, &activity, self.buffer_size),
+        );
+        futures::pin_mut!(copying);
+
+        match self.idle_timeout {
+            Some(timeout) => {
+                let idle = idle(&activity, timeout);
+                futures::pin_mut!(idle);
+                match future::select(copying, idle).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(((), _)) => {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "connection idle"))
+                    }
+                }
+            }
+            None => copying.await,
+        }
+        .map(|(sent, received)| ProxyStats { sent, received })
+    }
+}
+
+// Copy data until the reader is closed, and then close the writer.
+async fn copy<R, W>(
+    mut reader: R,
+    mut writer: W,
+    activity: &Activity,
+    buffer_size: usize,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buffer_size];
+    let mut total = 0;
+    loop {
+        let bytes = reader.read(&mut buf).await?;
+        if bytes == 0 {
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+        activity.touch();
+        writer.write_all(&buf[0..bytes]).await?;
+        total += bytes as u64;
+    }
+}
+
+// Complete when there has been no activity for `timeout`.
+async fn idle(activity: &Activity, timeout: Duration) {
+    loop {
+        let deadline = activity.last() + timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        delay_until(deadline).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Shutdown;
+    use tokio::net::TcpListener;
+    use tokio::time::delay_for;
+
+    // Connect two streams to each other over loopback.
+    async fn connected() -> (TcpStream, TcpStream) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stream, accepted) = future::join(TcpStream::connect(addr), listener.accept()).await;
+        (stream.unwrap(), accepted.unwrap().0)
+    }
+
+    // Start a proxy between a client and a server and return the
+    // streams of the client and the server.
+    async fn start(
+        proxy: Proxy,
+    ) -> (
+        TcpStream,
+        TcpStream,
+        tokio::task::JoinHandle<io::Result<ProxyStats>>,
+    ) {
+        let (client, mut proxy_client) = connected().await;
+        let (mut proxy_upstream, server) = connected().await;
+        let handle =
+            tokio::spawn(async move { proxy.run(&mut proxy_client, &mut proxy_upstream).await });
+        (client, server, handle)
+    }
+
+    #[tokio::test]
+    async fn pass_on_half_close() {
+        let (mut client, mut server, handle) = start(Proxy::new()).await;
+
+        // The server sees the end of the request while the client can
+        // still read the response.
+        client.write_all(b"request").await.unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        server.write_all(b"response").await.unwrap();
+        server.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let stats = handle.await.unwrap().unwrap();
+        assert_eq!(
+            stats,
+            ProxyStats {
+                sent: 7,
+                received: 8
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn close_idle_connections() {
+        let timeout = Duration::from_millis(50);
+        let start_time = Instant::now();
+        let proxy = Proxy::new().idle_timeout(timeout);
+        let (mut client, mut server, handle) = start(proxy).await;
+
+        // Traffic in either direction keeps the connections open.
+        let mut buf = [0; 4];
+        for _ in 0..3 {
+            client.write_all(b"ping").await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            delay_for(Duration::from_millis(30)).await;
+            server.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            delay_for(Duration::from_millis(30)).await;
+        }
+        assert!(start_time.elapsed() > timeout * 3);
+
+        let err = handle.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Bounded queue with configurable overflow behavior.
+//!
+//! A bounded channel always makes the sender wait when the channel is
+//! full. When fanning out data to several destinations, this means
+//! that the slowest destination dictates the pace of all of them. The
+//! queue in this module instead lets the sender pick what should
+//! happen when the queue is full: wait for room, drop the oldest item
+//! in the queue, drop the new item, or disconnect the receiver.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// What to do when sending to a full queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until there is room in the queue.
+    Block,
+
+    /// Drop the oldest item in the queue to make room for the new
+    /// item.
+    DropOldest,
+
+    /// Drop the new item.
+    DropNewest,
+
+    /// Close the queue and drop all items in it. The receiver gets
+    /// `None` and all following sends fail.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy '{}'", s)),
+        }
+    }
+}
+
+/// Error returned when sending to a closed queue.
+///
+/// The item that could not be sent is returned in the error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Closed<T>(pub T);
+
+impl<T> fmt::Display for Closed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queue is closed")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for Closed<T> {}
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    closed: bool,
+    dropped: u64,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<State<T>>,
+
+    // Notified when an item is pushed or the queue is closed.
+    items: Notify,
+
+    // Notified when an item is popped or the queue is closed.
+    space: Notify,
+}
+
+/// Sending half of a queue.
+#[derive(Debug)]
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half of a queue.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Create a queue holding at most `capacity` items, using `policy`
+/// when it is full.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn bounded<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "queue capacity must be non-zero");
+    let shared = Arc::new(Shared {
+        capacity,
+        policy,
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            senders: 1,
+            closed: false,
+            dropped: 0,
+        }),
+        items: Notify::new(),
+        space: Notify::new(),
+    });
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Send an item to the queue.
+    ///
+    /// This only waits if the queue is full and the policy is
+    /// `Block`. Items dropped because of the policy are counted, but
+    /// are not errors.
+    pub async fn send(&self, item: T) -> Result<(), Closed<T>> {
+        let shared = &self.shared;
+        loop {
+            {
+                let mut state = shared.state.lock().unwrap();
+                if state.closed {
+                    // Pass on the notification to other blocked senders.
+                    shared.space.notify();
+                    return Err(Closed(item));
+                }
+                if state.items.len() < shared.capacity {
+                    state.items.push_back(item);
+                    // Blocked senders are woken one at a time, so pass
+                    // on the notification if there is still room.
+                    if state.items.len() < shared.capacity {
+                        shared.space.notify();
+                    }
+                    shared.items.notify();
+                    return Ok(());
+                }
+                match shared.policy {
+                    OverflowPolicy::Block => {}
+                    OverflowPolicy::DropOldest => {
+                        state.items.pop_front();
+                        state.items.push_back(item);
+                        state.dropped += 1;
+                        shared.items.notify();
+                        return Ok(());
+                    }
+                    OverflowPolicy::DropNewest => {
+                        state.dropped += 1;
+                        return Ok(());
+                    }
+                    OverflowPolicy::Disconnect => {
+                        state.dropped += state.items.len() as u64;
+                        state.items.clear();
+                        state.closed = true;
+                        shared.items.notify();
+                        shared.space.notify();
+                        return Err(Closed(item));
+                    }
+                }
+            }
+            shared.space.notified().await;
+        }
+    }
+
+    /// Number of items dropped because of the overflow policy.
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+
+    /// Number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    /// Check if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check if the queue is closed.
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.closed = true;
+            self.shared.items.notify();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next item from the queue.
+    ///
+    /// Returns `None` when the queue is closed and empty, that is,
+    /// when all senders are dropped or the queue was disconnected.
+    pub async fn recv(&mut self) -> Option<T> {
+        let shared = &self.shared;
+        loop {
+            {
+                let mut state = shared.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    shared.space.notify();
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            shared.items.notified().await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.items.clear();
+        self.shared.space.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    // Send all items, returning the number of failed sends.
+    async fn send_all(sender: &Sender<u32>, items: &[u32]) -> usize {
+        let mut failed = 0;
+        for &item in items {
+            if sender.send(item).await.is_err() {
+                failed += 1;
+            }
+        }
+        failed
+    }
+
+    // Receive items until the queue is empty or closed.
+    async fn recv_all(receiver: &mut Receiver<u32>) -> Vec<u32> {
+        let mut items = Vec::new();
+        while let Ok(Some(item)) = timeout(Duration::from_millis(10), receiver.recv()).await {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!("block".parse(), Ok(OverflowPolicy::Block));
+        assert_eq!("drop-oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert_eq!("drop-newest".parse(), Ok(OverflowPolicy::DropNewest));
+        assert_eq!("disconnect".parse(), Ok(OverflowPolicy::Disconnect));
+        assert!("drop".parse::<OverflowPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (sender, mut receiver) = bounded(2, OverflowPolicy::Block);
+        assert_eq!(send_all(&sender, &[1, 2]).await, 0);
+        assert_eq!(sender.len(), 2);
+
+        // The third item does not fit, so the send waits.
+        let blocked = timeout(Duration::from_millis(10), sender.send(3)).await;
+        assert!(blocked.is_err());
+
+        let sender = tokio::spawn(async move {
+            sender.send(3).await.unwrap();
+            sender
+        });
+        assert_eq!(receiver.recv().await, Some(1));
+        let sender = sender.await.unwrap();
+        assert_eq!(recv_all(&mut receiver).await, vec![2, 3]);
+        assert_eq!(sender.dropped(), 0);
+        assert!(!sender.is_closed());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest_items() {
+        let (sender, mut receiver) = bounded(3, OverflowPolicy::DropOldest);
+        assert_eq!(send_all(&sender, &[1, 2, 3, 4, 5]).await, 0);
+        assert_eq!(sender.len(), 3);
+        assert_eq!(sender.dropped(), 2);
+        assert_eq!(recv_all(&mut receiver).await, vec![3, 4, 5]);
+        assert!(!sender.is_closed());
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_oldest_items() {
+        let (sender, mut receiver) = bounded(3, OverflowPolicy::DropNewest);
+        assert_eq!(send_all(&sender, &[1, 2, 3, 4, 5]).await, 0);
+        assert_eq!(sender.len(), 3);
+        assert_eq!(sender.dropped(), 2);
+        assert_eq!(recv_all(&mut receiver).await, vec![1, 2, 3]);
+        assert!(!sender.is_closed());
+    }
+
+    #[tokio::test]
+    async fn disconnect_drops_all_items() {
+        let (sender, mut receiver) = bounded(3, OverflowPolicy::Disconnect);
+        assert_eq!(send_all(&sender, &[1, 2, 3]).await, 0);
+        assert_eq!(sender.send(4).await, Err(Closed(4)));
+        assert!(sender.is_closed());
+        assert!(sender.is_empty());
+        assert_eq!(sender.dropped(), 3);
+        assert_eq!(receiver.recv().await, None);
+
+        // Later sends fail without counting as dropped.
+        assert_eq!(sender.send(5).await, Err(Closed(5)));
+        assert_eq!(sender.dropped(), 3);
+    }
+
+    #[tokio::test]
+    async fn dropping_senders_closes_queue() {
+        let (sender, mut receiver) = bounded(3, OverflowPolicy::Block);
+        let other = sender.clone();
+        sender.send(1).await.unwrap();
+        drop(sender);
+        other.send(2).await.unwrap();
+        drop(other);
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn dropping_receiver_closes_queue() {
+        let (sender, receiver) = bounded(3, OverflowPolicy::Block);
+        sender.send(1).await.unwrap();
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert!(sender.is_empty());
+        assert_eq!(sender.send(2).await, Err(Closed(2)));
+    }
+}
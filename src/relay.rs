@@ -0,0 +1,638 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Relaying data to downstream TCP servers.
+//!
+//! Since a `TcpStream` cannot be cloned, each downstream connection
+//! is owned by a dedicated writer task that receives the data to write
+//! over a channel. The sending side of the channel can be cloned, so
+//! any number of client tasks can share the same downstream
+//! connection. Note that data from different clients is interleaved on
+//! the downstream connection in the chunks it was sent.
+//!
+//! Each downstream has its own bounded queue, so a slow downstream
+//! server does not stall the other ones unless the `OverflowPolicy`
+//! of the queue is to block. If the policy is to disconnect, a
+//! downstream that falls behind is closed for good.
+//!
+//! The writer task connects to the downstream server when it is
+//! spawned and keeps track of the health of the connection. If the
+//! connection cannot be established, or is lost, the downstream is
+//! marked as unhealthy and the writer task reconnects using
+//! exponential backoff. Data sent while the downstream is unhealthy
+//! is either buffered until the connection is back or dropped,
+//! depending on the `UnhealthyPolicy`. This means that a failing
+//! downstream server does not affect the clients or the other
+//! downstream servers.
+
+use crate::queue::{self, OverflowPolicy};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, delay_for};
+
+/// Error returned when data cannot be sent to a downstream server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError {
+    pub addr: SocketAddr,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "downstream {} is closed", self.addr)
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// What to do with data sent while the downstream is unhealthy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnhealthyPolicy {
+    /// Buffer at most this many chunks of data and write them when
+    /// the connection is back. If the buffer is full, the oldest
+    /// chunk is dropped.
+    Buffer(usize),
+
+    /// Drop the data.
+    Drop,
+}
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// Delay before the first reconnection attempt.
+    pub initial: Duration,
+
+    /// Maximum delay between reconnection attempts.
+    pub max: Duration,
+
+    /// Factor to multiply the delay with after each failed attempt.
+    pub factor: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            factor: 2,
+        }
+    }
+}
+
+impl Backoff {
+    fn next(&self, delay: Duration) -> Duration {
+        std::cmp::min(delay * self.factor, self.max)
+    }
+}
+
+/// Options for a downstream connection.
+#[derive(Debug, Clone)]
+pub struct DownstreamOptions {
+    capacity: usize,
+    overflow: OverflowPolicy,
+    backoff: Backoff,
+    connect_timeout: Duration,
+    policy: UnhealthyPolicy,
+}
+
+impl Default for DownstreamOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DownstreamOptions {
+    /// Create options with the default values.
+    pub fn new() -> Self {
+        Self {
+            capacity: 32,
+            overflow: OverflowPolicy::Block,
+            backoff: Backoff::default(),
+            connect_timeout: Duration::from_secs(5),
+            policy: UnhealthyPolicy::Buffer(32),
+        }
+    }
+
+    /// Set the number of chunks of data that can wait in the queue
+    /// to the writer task.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set what to do when the queue to the writer task is full. The
+    /// default is to wait for room in the queue.
+    pub fn overflow(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow = policy;
+        self
+    }
+
+    /// Set the backoff between reconnection attempts.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set the maximum time to wait for a connection attempt.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set the policy for data sent while the downstream is
+    /// unhealthy.
+    pub fn unhealthy_policy(mut self, policy: UnhealthyPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+/// Counters for a downstream connection.
+#[derive(Debug, Default)]
+struct Counters {
+    healthy: AtomicBool,
+    connects: AtomicU64,
+    failures: AtomicU64,
+    chunks_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    chunks_dropped: AtomicU64,
+    chunks_buffered: AtomicU64,
+}
+
+/// Snapshot of the counters of a downstream connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownstreamStats {
+    /// If the downstream is currently connected.
+    pub healthy: bool,
+
+    /// Number of successful connection attempts.
+    pub connects: u64,
+
+    /// Number of failed connection attempts and lost connections.
+    pub failures: u64,
+
+    /// Number of chunks written to the downstream server.
+    pub chunks_sent: u64,
+
+    /// Number of bytes written to the downstream server.
+    pub bytes_sent: u64,
+
+    /// Number of chunks dropped because the downstream was unhealthy.
+    pub chunks_dropped: u64,
+
+    /// Number of chunks dropped because the queue was full.
+    pub chunks_overflowed: u64,
+
+    /// Number of chunks currently in the queue.
+    pub chunks_queued: u64,
+
+    /// Number of chunks currently buffered waiting for a connection.
+    pub chunks_buffered: u64,
+}
+
+impl fmt::Display for DownstreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connects={} failures={} sent={} ({} bytes) dropped={} overflowed={} queued={} buffered={}",
+            if self.healthy { "healthy" } else { "unhealthy" },
+            self.connects,
+            self.failures,
+            self.chunks_sent,
+            self.bytes_sent,
+            self.chunks_dropped,
+            self.chunks_overflowed,
+            self.chunks_queued,
+            self.chunks_buffered
+        )
+    }
+}
+
+/// Handle to a shared connection to a downstream server.
+#[derive(Debug, Clone)]
+pub struct Downstream {
+    addr: SocketAddr,
+    sender: queue::Sender<Bytes>,
+    counters: Arc<Counters>,
+}
+
+impl Downstream {
+    /// Create a downstream connection to `addr`.
+    ///
+    /// This spawns the writer task, so it has to be called from
+    /// inside the runtime.
+    pub fn spawn(addr: SocketAddr, options: DownstreamOptions) -> Self {
+        let (sender, receiver) = queue::bounded(options.capacity, options.overflow);
+        let counters = Arc::new(Counters::default());
+        let writer = Writer {
+            addr,
+            options,
+            receiver,
+            counters: counters.clone(),
+            pending: VecDeque::new(),
+        };
+        tokio::spawn(writer.run());
+        Self {
+            addr,
+            sender,
+            counters,
+        }
+    }
+
+    /// Address of the downstream server.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Check if the downstream is currently connected.
+    pub fn is_healthy(&self) -> bool {
+        self.counters.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Check if the downstream is closed, meaning that no more data
+    /// can be sent to it.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Get a snapshot of the counters of the downstream.
+    pub fn stats(&self) -> DownstreamStats {
+        let counters = &self.counters;
+        DownstreamStats {
+            healthy: counters.healthy.load(Ordering::Relaxed),
+            connects: counters.connects.load(Ordering::Relaxed),
+            failures: counters.failures.load(Ordering::Relaxed),
+            chunks_sent: counters.chunks_sent.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            chunks_dropped: counters.chunks_dropped.load(Ordering::Relaxed),
+            chunks_overflowed: self.sender.dropped(),
+            chunks_queued: self.sender.len() as u64,
+            chunks_buffered: counters.chunks_buffered.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Send data to the downstream server.
+    ///
+    /// This does not wait for the data to be written. If the queue to
+    /// the writer task is full, what happens depends on the overflow
+    /// policy.
+    pub async fn send(&self, data: Bytes) -> Result<(), SendError> {
+        let addr = self.addr;
+        self.sender.send(data).await.map_err(|_| SendError { addr })
+    }
+}
+
+// Outcome of a connection attempt.
+enum Attempt {
+    Connected(TcpStream),
+    Failed,
+
+    // The channel was closed, so the writer task should stop.
+    Closed,
+}
+
+// State of the writer task.
+struct Writer {
+    addr: SocketAddr,
+    options: DownstreamOptions,
+    receiver: queue::Receiver<Bytes>,
+    counters: Arc<Counters>,
+
+    // Data waiting for the connection to come back.
+    pending: VecDeque<Bytes>,
+}
+
+impl Writer {
+    async fn run(mut self) {
+        let mut delay = self.options.backoff.initial;
+        loop {
+            match self.connect().await {
+                Attempt::Connected(stream) => {
+                    delay = self.options.backoff.initial;
+                    if !self.serve(stream).await {
+                        break;
+                    }
+                }
+                Attempt::Failed => {
+                    if !self.wait(delay).await {
+                        break;
+                    }
+                    delay = self.options.backoff.next(delay);
+                }
+                Attempt::Closed => break,
+            }
+        }
+
+        // Data still waiting for a connection is lost when the channel
+        // is closed before the connection is back.
+        let lost = self.pending.len() as u64;
+        self.counters
+            .chunks_dropped
+            .fetch_add(lost, Ordering::Relaxed);
+        self.pending.clear();
+        self.update_buffered();
+        self.counters.healthy.store(false, Ordering::Relaxed);
+        println!("Downstream {}: closed", self.addr);
+    }
+
+    // Try to connect to the downstream server. Since this can take up
+    // to the connect timeout, data is handled according to the policy
+    // meanwhile, so that the queue does not fill up.
+    async fn connect(&mut self) -> Attempt {
+        let connect = time::timeout(self.options.connect_timeout, TcpStream::connect(self.addr));
+        tokio::pin!(connect);
+        let result = loop {
+            tokio::select! {
+                result = &mut connect => match result {
+                    Ok(result) => break result,
+                    Err(err) => break Err(err.into()),
+                },
+                data = self.receiver.recv() => match data {
+                    Some(data) => self.unhealthy(data),
+                    None => return Attempt::Closed,
+                },
+            }
+        };
+        match result {
+            Ok(stream) => {
+                println!("Downstream {}: connected", self.addr);
+                self.counters.connects.fetch_add(1, Ordering::Relaxed);
+                self.counters.healthy.store(true, Ordering::Relaxed);
+                Attempt::Connected(stream)
+            }
+            Err(err) => {
+                println!("Downstream {}: unable to connect: {}", self.addr, err);
+                self.counters.failures.fetch_add(1, Ordering::Relaxed);
+                Attempt::Failed
+            }
+        }
+    }
+
+    // Write data to the connection until it fails. Returns false if
+    // the channel is closed, meaning that the writer task should stop.
+    async fn serve(&mut self, mut stream: TcpStream) -> bool {
+        let mut buf = [0; 1024];
+        loop {
+            let data = match self.pending.pop_front() {
+                Some(data) => {
+                    self.update_buffered();
+                    data
+                }
+                None => {
+                    // We read from the connection only to notice when
+                    // the downstream server closes it.
+                    tokio::select! {
+                        data = self.receiver.recv() => match data {
+                            Some(data) => data,
+                            None => return false,
+                        },
+                        result = stream.read(&mut buf) => {
+                            match result {
+                                Ok(0) => println!("Downstream {}: connection closed", self.addr),
+                                Ok(_) => continue,
+                                Err(err) => println!("Downstream {}: read failed: {}", self.addr, err),
+                            }
+                            self.disconnected();
+                            return true;
+                        }
+                    }
+                }
+            };
+
+            match stream.write_all(&data).await {
+                Ok(()) => {
+                    self.counters.chunks_sent.fetch_add(1, Ordering::Relaxed);
+                    self.counters
+                        .bytes_sent
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    println!("Downstream {}: write failed: {}", self.addr, err);
+                    self.disconnected();
+                    self.unhealthy(data);
+                    return true;
+                }
+            }
+        }
+    }
+
+    // Wait before the next connection attempt, handling data according
+    // to the policy meanwhile. Returns false if the channel is closed.
+    async fn wait(&mut self, duration: Duration) -> bool {
+        let mut delay = delay_for(duration);
+        loop {
+            tokio::select! {
+                _ = &mut delay => return true,
+                data = self.receiver.recv() => match data {
+                    Some(data) => self.unhealthy(data),
+                    None => return false,
+                },
+            }
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.counters.healthy.store(false, Ordering::Relaxed);
+        self.counters.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Handle data that could not be written.
+    fn unhealthy(&mut self, data: Bytes) {
+        match self.options.policy {
+            UnhealthyPolicy::Buffer(size) => {
+                self.pending.push_back(data);
+                while self.pending.len() > size {
+                    self.pending.pop_front();
+                    self.counters.chunks_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                self.update_buffered();
+            }
+            UnhealthyPolicy::Drop => {
+                self.counters.chunks_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn update_buffered(&self) {
+        let buffered = self.pending.len() as u64;
+        self.counters
+            .chunks_buffered
+            .store(buffered, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Get an address where no server is listening.
+    async fn unused_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    // Wait until `check` returns true for the stats of `downstream`.
+    async fn wait_for<F>(downstream: &Downstream, check: F) -> DownstreamStats
+    where
+        F: Fn(&DownstreamStats) -> bool,
+    {
+        let poll = async {
+            loop {
+                let stats = downstream.stats();
+                if check(&stats) {
+                    return stats;
+                }
+                delay_for(Duration::from_millis(5)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(5), poll)
+            .await
+            .unwrap_or_else(|_| panic!("timed out with {}", downstream.stats()))
+    }
+
+    async fn read_exact(stream: &mut TcpStream, count: usize) -> Vec<u8> {
+        let mut buf = vec![0; count];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    fn options(policy: UnhealthyPolicy) -> DownstreamOptions {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(40),
+            factor: 2,
+        };
+        DownstreamOptions::new()
+            .backoff(backoff)
+            .unhealthy_policy(policy)
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let backoff = Backoff::default();
+        let mut delay = backoff.initial;
+        let mut delays = Vec::new();
+        for _ in 0..9 {
+            delays.push(delay.as_millis());
+            delay = backoff.next(delay);
+        }
+        assert_eq!(
+            delays,
+            vec![100, 200, 400, 800, 1600, 3200, 6400, 10000, 10000]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_and_count() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let downstream = Downstream::spawn(addr, options(UnhealthyPolicy::Drop));
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // Data sent while connecting would be dropped, so wait until
+        // the downstream is connected.
+        wait_for(&downstream, |stats| stats.healthy).await;
+        downstream.send(Bytes::from("hello")).await.unwrap();
+        downstream.send(Bytes::from("world")).await.unwrap();
+        assert_eq!(read_exact(&mut stream, 10).await, b"helloworld");
+
+        let stats = wait_for(&downstream, |stats| stats.chunks_sent == 2).await;
+        assert!(stats.healthy);
+        assert_eq!(stats.connects, 1);
+        assert_eq!(stats.failures, 0);
+        assert_eq!(stats.bytes_sent, 10);
+        assert_eq!(stats.chunks_dropped, 0);
+
+        // Closing the connection on the server side makes the
+        // downstream reconnect.
+        drop(stream);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let stats = wait_for(&downstream, |stats| stats.connects == 2).await;
+        assert_eq!(stats.failures, 1);
+        downstream.send(Bytes::from("again")).await.unwrap();
+        assert_eq!(read_exact(&mut stream, 5).await, b"again");
+    }
+
+    #[tokio::test]
+    async fn reconnect_and_write_buffered() {
+        let addr = unused_addr().await;
+        let downstream = Downstream::spawn(addr, options(UnhealthyPolicy::Buffer(2)));
+        for data in &["one", "two", "six"] {
+            downstream.send(Bytes::from(*data)).await.unwrap();
+        }
+
+        // The oldest chunk is dropped, since the buffer only holds two
+        // chunks.
+        let stats = wait_for(&downstream, |stats| {
+            stats.chunks_buffered == 2 && stats.failures >= 2
+        })
+        .await;
+        assert!(!stats.healthy);
+        assert_eq!(stats.connects, 0);
+        assert_eq!(stats.chunks_dropped, 1);
+
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_exact(&mut stream, 6).await, b"twosix");
+        let stats = wait_for(&downstream, |stats| stats.chunks_sent == 2).await;
+        assert!(stats.healthy);
+        assert_eq!(stats.connects, 1);
+        assert_eq!(stats.chunks_buffered, 0);
+    }
+
+    #[tokio::test]
+    async fn drop_while_unhealthy() {
+        let addr = unused_addr().await;
+        let downstream = Downstream::spawn(addr, options(UnhealthyPolicy::Drop));
+        downstream.send(Bytes::from("lost")).await.unwrap();
+        downstream.send(Bytes::from("lost")).await.unwrap();
+        let stats = wait_for(&downstream, |stats| stats.chunks_dropped == 2).await;
+        assert_eq!(stats.chunks_buffered, 0);
+
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        wait_for(&downstream, |stats| stats.healthy).await;
+        downstream.send(Bytes::from("kept")).await.unwrap();
+        assert_eq!(read_exact(&mut stream, 4).await, b"kept");
+    }
+
+    #[tokio::test]
+    async fn count_buffered_as_dropped_on_close() {
+        let addr = unused_addr().await;
+        let downstream = Downstream::spawn(addr, options(UnhealthyPolicy::Buffer(10)));
+        downstream.send(Bytes::from("one")).await.unwrap();
+        downstream.send(Bytes::from("two")).await.unwrap();
+        wait_for(&downstream, |stats| stats.chunks_buffered == 2).await;
+
+        // Closing the channel stops the writer task, which then drops
+        // the buffered data.
+        let counters = downstream.counters.clone();
+        drop(downstream);
+        let closed = async {
+            while counters.chunks_buffered.load(Ordering::Relaxed) > 0 {
+                delay_for(Duration::from_millis(5)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(5), closed).await.unwrap();
+        assert_eq!(counters.chunks_dropped.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,781 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Reliable delivery of messages over UDP.
+//!
+//! UDP datagrams can be lost, duplicated, and reordered. This module
+//! adds a small protocol on top of UDP that delivers each message
+//! exactly once and in the order it was sent:
+//!
+//! - Each call to `ReliableSender::send` starts a new session with a
+//!   random session identifier. Each message is sent in a data packet
+//!   with the session identifier and a sequence number, starting from
+//!   zero in each session.
+//!
+//! - The receiver acknowledges each data packet it gets, including
+//!   duplicates, since the acknowledgement might have been lost.
+//!
+//! - The sender keeps a window of unacknowledged messages and
+//!   retransmits a message if it is not acknowledged within the
+//!   timeout. If a message is not acknowledged after the maximum
+//!   number of retransmissions, the sender gives up.
+//!
+//! - The receiver drops duplicates and buffers messages that arrive
+//!   early until the missing messages have been received.
+//!
+//! A packet starts with a one-byte type, followed by the session
+//! identifier and the sequence number as 32-bit big-endian integers.
+//! Data packets are followed by the message.
+//!
+//! The receiver keeps the state of each session separately, so a
+//! sender can send several batches to the same receiver, or start over
+//! after a failed send, without changing port. Sessions that have been
+//! idle for longer than the idle timeout of the receiver are removed.
+
+use crate::datagram::DatagramBuffer;
+use crate::random::Random;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{delay_until, Instant};
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const HEADER_SIZE: usize = 9;
+
+// Maximum number of messages the receiver buffers for each sender
+// while waiting for a missing message.
+const MAX_BUFFERED: u32 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Packet {
+    Data {
+        session: u32,
+        seq: u32,
+        payload: Bytes,
+    },
+    Ack {
+        session: u32,
+        seq: u32,
+    },
+}
+
+impl Packet {
+    fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE);
+        match self {
+            Packet::Data {
+                session,
+                seq,
+                payload,
+            } => {
+                buf.reserve(payload.len());
+                buf.put_u8(DATA);
+                buf.put_u32(*session);
+                buf.put_u32(*seq);
+                buf.put_slice(payload);
+            }
+            Packet::Ack { session, seq } => {
+                buf.put_u8(ACK);
+                buf.put_u32(*session);
+                buf.put_u32(*seq);
+            }
+        }
+        buf.freeze()
+    }
+
+    fn decode(data: &[u8]) -> Option<Packet> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        let session = u32::from_be_bytes(data[1..5].try_into().unwrap());
+        let seq = u32::from_be_bytes(data[5..HEADER_SIZE].try_into().unwrap());
+        match data[0] {
+            DATA => Some(Packet::Data {
+                session,
+                seq,
+                payload: Bytes::copy_from_slice(&data[HEADER_SIZE..]),
+            }),
+            ACK if data.len() == HEADER_SIZE => Some(Packet::Ack { session, seq }),
+            _ => None,
+        }
+    }
+}
+
+/// Error returned when messages could not be delivered.
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// Sending or receiving failed.
+    Io(io::Error),
+
+    /// The message with this sequence number was not acknowledged
+    /// after the maximum number of retransmissions.
+    TimedOut { seq: u32 },
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::Io(err) => write!(f, "{}", err),
+            DeliveryError::TimedOut { seq } => {
+                write!(f, "message {} was not acknowledged", seq)
+            }
+        }
+    }
+}
+
+impl Error for DeliveryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DeliveryError::Io(err) => Some(err),
+            DeliveryError::TimedOut { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for DeliveryError {
+    fn from(err: io::Error) -> Self {
+        DeliveryError::Io(err)
+    }
+}
+
+/// Counters for the messages sent by a `ReliableSender`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SendStats {
+    /// Number of messages sent, not counting retransmissions.
+    pub sent: u64,
+
+    /// Number of retransmissions.
+    pub retransmitted: u64,
+
+    /// Number of acknowledgements for messages that were already
+    /// acknowledged.
+    pub duplicate_acks: u64,
+}
+
+impl fmt::Display for SendStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent={} retransmitted={} duplicate acks={}",
+            self.sent, self.retransmitted, self.duplicate_acks
+        )
+    }
+}
+
+// Message waiting for an acknowledgement.
+struct Unacked {
+    packet: Bytes,
+    deadline: Instant,
+    retries: u32,
+}
+
+/// Sender of reliable messages.
+#[derive(Debug)]
+pub struct ReliableSender {
+    window: usize,
+    timeout: Duration,
+    retries: u32,
+    // Source of session identifiers. It is not cloned, so that two
+    // senders do not pick the same identifiers.
+    random: Random,
+}
+
+impl Default for ReliableSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReliableSender {
+    /// Create a sender with a window of 16 messages, a retransmission
+    /// timeout of 200 milliseconds, and at most 10 retransmissions of
+    /// each message.
+    pub fn new() -> Self {
+        Self {
+            window: 16,
+            timeout: Duration::from_millis(200),
+            retries: 10,
+            random: Random::from_time(),
+        }
+    }
+
+    /// Set the number of messages that can be sent without being
+    /// acknowledged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` is zero.
+    pub fn window(mut self, window: usize) -> Self {
+        assert!(window > 0, "window must be non-zero");
+        self.window = window;
+        self
+    }
+
+    /// Set the time to wait for an acknowledgement before
+    /// retransmitting a message.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of retransmissions of each message.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Send `messages` to `dest` using `socket` and wait until all of
+    /// them are acknowledged.
+    ///
+    /// The messages are sent in a new session, so the sender can be
+    /// used for any number of calls to the same or different
+    /// destinations. Datagrams on the socket that are not
+    /// acknowledgements from `dest` for this session are ignored.
+    pub async fn send<I>(
+        &mut self,
+        socket: &mut UdpSocket,
+        dest: SocketAddr,
+        messages: I,
+    ) -> Result<SendStats, DeliveryError>
+    where
+        I: IntoIterator<Item = Bytes>,
+    {
+        let mut stats = SendStats::default();
+        let mut messages = messages.into_iter();
+        let mut unacked: BTreeMap<u32, Unacked> = BTreeMap::new();
+        let mut next_seq = 0;
+        let session = self.random.next_u64() as u32;
+        let mut buf = [0; HEADER_SIZE + 1];
+        loop {
+            while unacked.len() < self.window {
+                let payload = match messages.next() {
+                    Some(payload) => payload,
+                    None => break,
+                };
+                let seq = next_seq;
+                next_seq += 1;
+                let packet = Packet::Data {
+                    session,
+                    seq,
+                    payload,
+                }
+                .encode();
+                socket.send_to(&packet, &dest).await?;
+                stats.sent += 1;
+                let deadline = Instant::now() + self.timeout;
+                unacked.insert(
+                    seq,
+                    Unacked {
+                        packet,
+                        deadline,
+                        retries: 0,
+                    },
+                );
+            }
+
+            let deadline = match unacked.values().map(|unacked| unacked.deadline).min() {
+                Some(deadline) => deadline,
+                None => return Ok(stats),
+            };
+            tokio::select! {
+                result = socket.recv_from(&mut buf) => {
+                    let (bytes, addr) = result?;
+                    if addr != dest {
+                        continue;
+                    }
+                    // Acknowledgements from earlier sessions are ignored.
+                    if let Some(Packet::Ack { session: acked, seq }) = Packet::decode(&buf[0..bytes]) {
+                        if acked == session && unacked.remove(&seq).is_none() {
+                            stats.duplicate_acks += 1;
+                        }
+                    }
+                }
+                _ = delay_until(deadline) => {
+                    let now = Instant::now();
+                    for (&seq, unacked) in unacked.iter_mut() {
+                        if unacked.deadline > now {
+                            continue;
+                        }
+                        if unacked.retries == self.retries {
+                            return Err(DeliveryError::TimedOut { seq });
+                        }
+                        socket.send_to(&unacked.packet, &dest).await?;
+                        unacked.retries += 1;
+                        unacked.deadline = now + self.timeout;
+                        stats.retransmitted += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Message delivered by a `ReliableReceiver`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// Address of the sender.
+    pub addr: SocketAddr,
+
+    /// Sequence number of the message.
+    pub seq: u32,
+
+    /// Contents of the message.
+    pub data: Bytes,
+}
+
+/// Counters for the packets received by a `ReliableReceiver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReceiveStats {
+    /// Number of messages delivered.
+    pub delivered: u64,
+
+    /// Number of duplicate messages dropped.
+    pub duplicates: u64,
+
+    /// Number of messages that arrived before an earlier message and
+    /// had to be buffered.
+    pub reordered: u64,
+
+    /// Number of packets that were not valid data packets.
+    pub invalid: u64,
+}
+
+impl fmt::Display for ReceiveStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "delivered={} duplicates={} reordered={} invalid={}",
+            self.delivered, self.duplicates, self.reordered, self.invalid
+        )
+    }
+}
+
+// State of the messages in one session.
+#[derive(Debug)]
+struct Peer {
+    expected: u32,
+    buffered: BTreeMap<u32, Bytes>,
+    last_active: Instant,
+}
+
+impl Peer {
+    fn new(now: Instant) -> Self {
+        Self {
+            expected: 0,
+            buffered: BTreeMap::new(),
+            last_active: now,
+        }
+    }
+}
+
+/// Receiver of reliable messages from any number of senders.
+#[derive(Debug)]
+pub struct ReliableReceiver {
+    socket: UdpSocket,
+    buf: DatagramBuffer,
+    peers: HashMap<(SocketAddr, u32), Peer>,
+    ready: VecDeque<Delivery>,
+    stats: ReceiveStats,
+    idle_timeout: Duration,
+    next_expiry: Instant,
+}
+
+impl ReliableReceiver {
+    /// Create a receiver using `socket`, receiving packets into `buf`.
+    ///
+    /// Sessions are removed after being idle for 60 seconds.
+    pub fn new(socket: UdpSocket, buf: DatagramBuffer) -> Self {
+        let idle_timeout = Duration::from_secs(60);
+        Self {
+            socket,
+            buf,
+            peers: HashMap::new(),
+            ready: VecDeque::new(),
+            stats: ReceiveStats::default(),
+            idle_timeout,
+            next_expiry: Instant::now() + idle_timeout,
+        }
+    }
+
+    /// Set the time after which the state of an idle session is
+    /// removed.
+    ///
+    /// Messages that arrive for a session after it was removed start
+    /// a new session, so the timeout should be well above the time a
+    /// sender keeps retransmitting.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self.next_expiry = Instant::now() + timeout;
+        self
+    }
+
+    /// Counters for the received packets.
+    pub fn stats(&self) -> ReceiveStats {
+        self.stats
+    }
+
+    /// Receive the next message.
+    ///
+    /// The messages from each sender are delivered in the order they
+    /// were sent, and each message is only delivered once.
+    pub async fn recv(&mut self) -> io::Result<Delivery> {
+        loop {
+            if let Some(delivery) = self.ready.pop_front() {
+                self.stats.delivered += 1;
+                return Ok(delivery);
+            }
+
+            let (bytes, addr) = self.socket.recv_from(self.buf.recv_buf()).await?;
+            let datagram = self.buf.datagram(bytes);
+            let (session, seq, payload) = match Packet::decode(datagram.data) {
+                Some(Packet::Data {
+                    session,
+                    seq,
+                    payload,
+                }) if !datagram.truncated => (session, seq, payload),
+                _ => {
+                    self.stats.invalid += 1;
+                    continue;
+                }
+            };
+
+            let now = Instant::now();
+            self.expire(now);
+            let peer = self
+                .peers
+                .entry((addr, session))
+                .or_insert_with(|| Peer::new(now));
+            peer.last_active = now;
+            if seq.wrapping_sub(peer.expected) >= MAX_BUFFERED {
+                // This is either a duplicate or too far ahead. In the
+                // latter case, it is dropped without acknowledging it,
+                // so that the sender retransmits it later.
+                if seq < peer.expected {
+                    self.stats.duplicates += 1;
+                    self.acknowledge(session, seq, addr).await?;
+                }
+                continue;
+            }
+            self.acknowledge(session, seq, addr).await?;
+
+            let peer = self.peers.get_mut(&(addr, session)).unwrap();
+            if seq != peer.expected {
+                if peer.buffered.insert(seq, payload).is_some() {
+                    self.stats.duplicates += 1;
+                } else {
+                    self.stats.reordered += 1;
+                }
+                continue;
+            }
+
+            self.ready.push_back(Delivery {
+                addr,
+                seq,
+                data: payload,
+            });
+            peer.expected += 1;
+            while let Some(data) = peer.buffered.remove(&peer.expected) {
+                self.ready.push_back(Delivery {
+                    addr,
+                    seq: peer.expected,
+                    data,
+                });
+                peer.expected += 1;
+            }
+        }
+    }
+
+    // Remove the sessions that have been idle for longer than the idle
+    // timeout. This is done at most once per idle timeout, so a session
+    // can be kept for up to twice the idle timeout.
+    fn expire(&mut self, now: Instant) {
+        if now < self.next_expiry {
+            return;
+        }
+        let idle_timeout = self.idle_timeout;
+        self.peers
+            .retain(|_, peer| now.duration_since(peer.last_active) < idle_timeout);
+        self.next_expiry = now + idle_timeout;
+    }
+
+    async fn acknowledge(&mut self, session: u32, seq: u32, addr: SocketAddr) -> io::Result<()> {
+        let packet = Packet::Ack { session, seq }.encode();
+        self.socket.send_to(&packet, &addr).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impairment::{Impairer, Impairment, Probability};
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    fn messages(count: usize) -> Vec<Bytes> {
+        (0..count)
+            .map(|index| Bytes::from(format!("message {}", index)))
+            .collect()
+    }
+
+    // Receive `count` messages and return them together with the
+    // receiver statistics.
+    async fn receive(socket: UdpSocket, count: usize) -> (Vec<Bytes>, ReceiveStats) {
+        let mut receiver = ReliableReceiver::new(socket, DatagramBuffer::new(64).unwrap());
+        let mut received = Vec::new();
+        while received.len() < count {
+            received.push(receiver.recv().await.unwrap().data);
+        }
+        (received, receiver.stats())
+    }
+
+    // Forward packets between the sender and `dest`. Packets from the
+    // sender are passed to `impair`, which returns the copies to
+    // forward and the delay of each copy. The sender is the first
+    // address that sends to the proxy.
+    async fn proxy<F>(mut socket: UdpSocket, dest: SocketAddr, mut impair: F)
+    where
+        F: FnMut(&[u8]) -> Vec<(Duration, Bytes)>,
+    {
+        let mut pending: Vec<(Instant, Bytes)> = Vec::new();
+        let mut sender = None;
+        let mut buf = [0; 64];
+        loop {
+            let next = pending.iter().map(|(at, _)| *at).min();
+            let due = delay_until(next.unwrap_or_else(|| Instant::now() + Duration::from_secs(1)));
+            tokio::select! {
+                result = socket.recv_from(&mut buf) => {
+                    let (bytes, addr) = result.unwrap();
+                    if addr == dest {
+                        if let Some(sender) = sender {
+                            socket.send_to(&buf[..bytes], &sender).await.unwrap();
+                        }
+                    } else {
+                        sender = Some(addr);
+                        let now = Instant::now();
+                        for (delay, data) in impair(&buf[..bytes]) {
+                            pending.push((now + delay, data));
+                        }
+                    }
+                }
+                _ = due => {
+                    let now = Instant::now();
+                    let (mut ready, waiting): (Vec<_>, Vec<_>) =
+                        pending.into_iter().partition(|(at, _)| *at <= now);
+                    pending = waiting;
+                    ready.sort_by_key(|(at, _)| *at);
+                    for (_, data) in ready {
+                        socket.send_to(&data, &dest).await.unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let data = Packet::Data {
+            session: 3,
+            seq: 7,
+            payload: Bytes::from_static(b"hello"),
+        };
+        assert_eq!(Packet::decode(&data.encode()), Some(data));
+        let ack = Packet::Ack { session: 3, seq: 7 };
+        assert_eq!(Packet::decode(&ack.encode()), Some(ack));
+        assert_eq!(Packet::decode(&[ACK, 0, 0, 0, 3, 0, 0, 0, 7, 0]), None);
+        assert_eq!(Packet::decode(&[DATA, 0, 0, 0, 3, 0, 0]), None);
+    }
+
+    #[tokio::test]
+    async fn deliver_in_order_when_reordered() {
+        let receiver = bind().await;
+        let receiver_addr = receiver.local_addr().unwrap();
+        let proxy_socket = bind().await;
+        let proxy_addr = proxy_socket.local_addr().unwrap();
+        // Hold back every third packet so that it arrives after the
+        // following packets.
+        let mut count = 0;
+        let impair = move |data: &[u8]| {
+            count += 1;
+            let delay = if count % 3 == 0 { 20 } else { 0 };
+            vec![(Duration::from_millis(delay), Bytes::copy_from_slice(data))]
+        };
+        tokio::spawn(proxy(proxy_socket, receiver_addr, impair));
+        let receiving = tokio::spawn(receive(receiver, 50));
+
+        let mut socket = bind().await;
+        let stats = ReliableSender::new()
+            .send(&mut socket, proxy_addr, messages(50))
+            .await
+            .unwrap();
+        let (received, receive_stats) = receiving.await.unwrap();
+        assert_eq!(received, messages(50));
+        assert_eq!(stats.sent, 50);
+        assert!(receive_stats.reordered > 0);
+        assert_eq!(receive_stats.delivered, 50);
+    }
+
+    #[tokio::test]
+    async fn drop_duplicates() {
+        let receiver = bind().await;
+        let receiver_addr = receiver.local_addr().unwrap();
+        let receiving = tokio::spawn(receive(receiver, 4));
+
+        // Send most messages twice, and the first message again after
+        // the second one. The receiver has read all duplicates when
+        // it has delivered the last message.
+        let mut socket = bind().await;
+        let packets: Vec<Bytes> = messages(4)
+            .into_iter()
+            .zip(0..)
+            .map(|(payload, seq)| {
+                Packet::Data {
+                    session: 1,
+                    seq,
+                    payload,
+                }
+                .encode()
+            })
+            .collect();
+        let order = [0, 0, 1, 1, 0, 2, 2, 3];
+        for &index in &order {
+            socket
+                .send_to(&packets[index as usize], &receiver_addr)
+                .await
+                .unwrap();
+        }
+
+        let (received, stats) = receiving.await.unwrap();
+        assert_eq!(received, messages(4));
+        assert_eq!(stats.delivered, 4);
+        assert_eq!(stats.duplicates, 4);
+        assert_eq!(stats.reordered, 0);
+
+        // The duplicates are acknowledged as well, since the sender
+        // might have missed the first acknowledgement.
+        let mut buf = [0; HEADER_SIZE];
+        for &index in &order {
+            let (bytes, _) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(
+                Packet::decode(&buf[..bytes]),
+                Some(Packet::Ack {
+                    session: 1,
+                    seq: index
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver_when_dropped() {
+        let receiver = bind().await;
+        let receiver_addr = receiver.local_addr().unwrap();
+        let proxy_socket = bind().await;
+        let proxy_addr = proxy_socket.local_addr().unwrap();
+        let mut impairer = Impairer::new(Impairment::new().drop(Probability::new(0.3)), 42);
+        let impair = move |data: &[u8]| {
+            impairer
+                .impair(data)
+                .into_iter()
+                .map(|copy| (copy.delay, copy.data))
+                .collect()
+        };
+        tokio::spawn(proxy(proxy_socket, receiver_addr, impair));
+        let receiving = tokio::spawn(receive(receiver, 50));
+
+        let mut socket = bind().await;
+        let stats = ReliableSender::new()
+            .timeout(Duration::from_millis(20))
+            .send(&mut socket, proxy_addr, messages(50))
+            .await
+            .unwrap();
+        let (received, receive_stats) = receiving.await.unwrap();
+        assert_eq!(received, messages(50));
+        assert_eq!(stats.sent, 50);
+        assert!(stats.retransmitted > 0);
+        assert_eq!(receive_stats.delivered, 50);
+    }
+
+    #[tokio::test]
+    async fn send_twice() {
+        let receiver = bind().await;
+        let receiver_addr = receiver.local_addr().unwrap();
+        let receiving = tokio::spawn(receive(receiver, 20));
+
+        let mut socket = bind().await;
+        let mut sender = ReliableSender::new();
+        for _ in 0..2 {
+            let stats = sender
+                .send(&mut socket, receiver_addr, messages(10))
+                .await
+                .unwrap();
+            assert_eq!(stats.sent, 10);
+        }
+        let (received, stats) = receiving.await.unwrap();
+        assert_eq!(received, [messages(10), messages(10)].concat());
+        assert_eq!(stats.duplicates, 0);
+    }
+
+    #[tokio::test]
+    async fn expire_idle_sessions() {
+        let socket = bind().await;
+        let receiver_addr = socket.local_addr().unwrap();
+        let mut receiver = ReliableReceiver::new(socket, DatagramBuffer::new(64).unwrap())
+            .idle_timeout(Duration::from_millis(50));
+        let mut sender = bind().await;
+        for session in 0..2 {
+            let packet = Packet::Data {
+                session,
+                seq: 0,
+                payload: Bytes::from_static(b"hello"),
+            };
+            sender
+                .send_to(&packet.encode(), &receiver_addr)
+                .await
+                .unwrap();
+            assert_eq!(receiver.recv().await.unwrap().seq, 0);
+            assert_eq!(receiver.peers.len(), 1);
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(receiver
+            .peers
+            .contains_key(&(sender.local_addr().unwrap(), 1)));
+    }
+
+    #[tokio::test]
+    async fn time_out_without_receiver() {
+        // Bind a socket and close it to get an address without a
+        // receiver.
+        let dest = bind().await.local_addr().unwrap();
+        let mut socket = bind().await;
+        let result = ReliableSender::new()
+            .timeout(Duration::from_millis(10))
+            .retries(3)
+            .send(&mut socket, dest, messages(1))
+            .await;
+        match result {
+            Err(DeliveryError::TimedOut { seq }) => assert_eq!(seq, 0),
+            result => panic!("expected a time out, got {:?}", result),
+        }
+    }
+}
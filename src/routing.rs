@@ -0,0 +1,571 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Routing of traffic to destinations.
+//!
+//! A relay can either send everything to all destinations, or act as
+//! a load balancer and pick one destination for each client or
+//! packet. The `Router` implements the different ways of picking a
+//! destination.
+//!
+//! Each pick returns a `Lease` that counts as an active connection to
+//! the destination until it is dropped. This is what the
+//! least-connections routing uses to balance the load, so the lease
+//! should be kept for as long as the destination is in use.
+//!
+//! Destinations that are closed or unhealthy can be marked as not
+//! available, and are then skipped by all routings until they are
+//! marked as available again.
+
+use crate::config::ConfigError;
+use crate::random::Random;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Number of points on the hash ring for each unit of weight.
+const POINTS_PER_WEIGHT: u32 = 100;
+
+/// Largest weight of a destination.
+pub const MAX_WEIGHT: u32 = 1000;
+
+/// How a relay handles its clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Forward traffic from the clients to the destinations and
+    /// ignore any replies.
+    Tee,
+
+    /// Give each client its own upstream connection or session and
+    /// forward replies back to it.
+    Proxy,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tee" => Ok(Mode::Tee),
+            "proxy" => Ok(Mode::Proxy),
+            _ => Err(format!("unknown mode '{}'", s)),
+        }
+    }
+}
+
+/// How to pick destinations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /// Send to all destinations.
+    Broadcast,
+
+    /// Pick the destinations in turn.
+    RoundRobin,
+
+    /// Pick the destination with the fewest active connections.
+    LeastConnections,
+
+    /// Pick the destination using consistent hashing on the source
+    /// address, so that the same source always goes to the same
+    /// destination as long as the destinations do not change.
+    ConsistentHash,
+
+    /// Pick a random destination with probability proportional to its
+    /// weight.
+    Weighted,
+}
+
+impl FromStr for Routing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "broadcast" => Ok(Routing::Broadcast),
+            "round-robin" => Ok(Routing::RoundRobin),
+            "least-connections" => Ok(Routing::LeastConnections),
+            "hash" => Ok(Routing::ConsistentHash),
+            "weighted" => Ok(Routing::Weighted),
+            _ => Err(format!("unknown routing '{}'", s)),
+        }
+    }
+}
+
+/// Weights of the destinations, parsed from a comma-separated list.
+///
+/// Each weight has to be at most `MAX_WEIGHT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Weights(pub Vec<u32>);
+
+impl FromStr for Weights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|weight| weight.trim().parse().map_err(|err| format!("{}", err)))
+            .collect::<Result<Vec<u32>, _>>()?;
+        if let Some(weight) = weights.iter().find(|&&weight| weight > MAX_WEIGHT) {
+            return Err(format!("weight {} is larger than {}", weight, MAX_WEIGHT));
+        }
+        if weights.iter().all(|&weight| weight == 0) {
+            return Err("at least one weight has to be positive".to_string());
+        }
+        Ok(Weights(weights))
+    }
+}
+
+impl fmt::Display for Weights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let weights: Vec<String> = self.0.iter().map(|weight| weight.to_string()).collect();
+        write!(f, "{}", weights.join(","))
+    }
+}
+
+/// Destinations picked by the router.
+#[derive(Debug)]
+pub enum Route {
+    /// Send to all destinations.
+    All,
+
+    /// Send to a single destination.
+    One(Lease),
+}
+
+/// Active connection to a destination.
+///
+/// The connection is counted as active until the lease is dropped.
+#[derive(Debug)]
+pub struct Lease {
+    index: usize,
+    active: Arc<Vec<AtomicUsize>>,
+}
+
+impl Lease {
+    /// Index of the destination.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.active[self.index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Router picking destinations using one of the routings.
+#[derive(Debug)]
+pub struct Router {
+    routing: Routing,
+    weights: Vec<u32>,
+    active: Arc<Vec<AtomicUsize>>,
+    available: Vec<bool>,
+    next: usize,
+    random: Random,
+
+    // Points on the hash ring, sorted by hash.
+    ring: Vec<(u64, usize)>,
+}
+
+impl Router {
+    /// Create a router for `count` destinations with equal weights.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero.
+    pub fn new(routing: Routing, count: usize) -> Self {
+        assert!(count > 0, "router needs at least one destination");
+        let mut router = Self {
+            routing,
+            weights: Vec::new(),
+            active: Arc::new((0..count).map(|_| AtomicUsize::new(0)).collect()),
+            available: vec![true; count],
+            next: 0,
+            random: Random::from_time(),
+            ring: Vec::new(),
+        };
+        router.set_weights(vec![1; count]);
+        router
+    }
+
+    /// Create a router for `count` destinations from the options of a
+    /// relay running in `mode`.
+    ///
+    /// If no routing is given, tee mode sends to all destinations and
+    /// proxy mode picks them in turn. Broadcast routing cannot be used
+    /// in proxy mode, since each client is forwarded to a single
+    /// destination.
+    pub fn configure(
+        mode: Mode,
+        routing: Option<Routing>,
+        count: usize,
+        weights: Option<&Weights>,
+        seed: Option<u64>,
+    ) -> Result<Self, ConfigError> {
+        if count == 0 {
+            return Err(ConfigError::InvalidValue {
+                name: "--dest".to_string(),
+                value: String::new(),
+                reason: "no destinations given".to_string(),
+            });
+        }
+        let routing = match (mode, routing) {
+            (Mode::Proxy, Some(Routing::Broadcast)) => {
+                return Err(ConfigError::InvalidValue {
+                    name: "--routing".to_string(),
+                    value: "broadcast".to_string(),
+                    reason: "broadcast routing cannot be used in proxy mode".to_string(),
+                })
+            }
+            (_, Some(routing)) => routing,
+            (Mode::Tee, None) => Routing::Broadcast,
+            (Mode::Proxy, None) => Routing::RoundRobin,
+        };
+        let mut router = Router::new(routing, count);
+        if let Some(weights) = weights {
+            if weights.0.len() != count {
+                return Err(ConfigError::InvalidValue {
+                    name: "--weights".to_string(),
+                    value: weights.to_string(),
+                    reason: "expected one weight for each destination".to_string(),
+                });
+            }
+            router = router.weights(&weights.0);
+        }
+        if let Some(seed) = seed {
+            router = router.seed(seed);
+        }
+        Ok(router)
+    }
+
+    /// Set the weights of the destinations. The weights are used by
+    /// the weighted and the consistent hash routings, and a
+    /// destination with weight zero is never picked by them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of weights is not the same as the number
+    /// of destinations, if any weight is larger than `MAX_WEIGHT`, or
+    /// if all weights are zero.
+    pub fn weights(mut self, weights: &[u32]) -> Self {
+        assert_eq!(
+            weights.len(),
+            self.active.len(),
+            "there has to be one weight for each destination"
+        );
+        assert!(
+            weights.iter().all(|&weight| weight <= MAX_WEIGHT),
+            "weights have to be at most {}",
+            MAX_WEIGHT
+        );
+        assert!(
+            weights.iter().any(|&weight| weight > 0),
+            "at least one weight has to be positive"
+        );
+        self.set_weights(weights.to_vec());
+        self
+    }
+
+    /// Seed the random number generator used by the weighted routing,
+    /// so that the same destinations are picked each run.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.random = Random::new(seed);
+        self
+    }
+
+    fn set_weights(&mut self, weights: Vec<u32>) {
+        self.ring.clear();
+        for (index, &weight) in weights.iter().enumerate() {
+            for point in 0..weight * POINTS_PER_WEIGHT {
+                self.ring.push((hash(&(index, point)), index));
+            }
+        }
+        self.ring.sort_unstable();
+        self.weights = weights;
+    }
+
+    /// Number of active connections to each destination.
+    pub fn active(&self) -> Vec<usize> {
+        self.active
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Mark the destination at `index` as available or not.
+    ///
+    /// All destinations are available when the router is created.
+    /// Destinations that are not available are never picked, and the
+    /// broadcast routing leaves it to the caller to skip them.
+    pub fn set_available(&mut self, index: usize, available: bool) {
+        self.available[index] = available;
+    }
+
+    /// Check if the destination at `index` is available.
+    pub fn is_available(&self, index: usize) -> bool {
+        self.available[index]
+    }
+
+    /// Pick destinations for traffic from `source`.
+    ///
+    /// Returns `None` if no destination is available, or if all
+    /// available destinations have weight zero for the weighted and
+    /// consistent hash routings.
+    pub fn route(&mut self, source: SocketAddr) -> Option<Route> {
+        let index = match self.routing {
+            Routing::Broadcast => {
+                return self.available.contains(&true).then_some(Route::All);
+            }
+            Routing::RoundRobin => self.round_robin()?,
+            Routing::LeastConnections => self.least_connections()?,
+            Routing::ConsistentHash => self.consistent_hash(source)?,
+            Routing::Weighted => self.weighted()?,
+        };
+        self.active[index].fetch_add(1, Ordering::Relaxed);
+        Some(Route::One(Lease {
+            index,
+            active: self.active.clone(),
+        }))
+    }
+
+    // Available destinations in round-robin order, starting with the
+    // one after the last pick.
+    fn in_turn(&mut self) -> impl Iterator<Item = usize> + '_ {
+        let count = self.active.len();
+        let start = self.next;
+        self.next = (self.next + 1) % count;
+        let available = &self.available;
+        (0..count)
+            .map(move |offset| (start + offset) % count)
+            .filter(move |&index| available[index])
+    }
+
+    fn round_robin(&mut self) -> Option<usize> {
+        let index = self.in_turn().next()?;
+        self.next = (index + 1) % self.active.len();
+        Some(index)
+    }
+
+    // Ties are broken in round-robin order, so that destinations
+    // without connections are picked in turn.
+    fn least_connections(&mut self) -> Option<usize> {
+        let active = self.active.clone();
+        self.in_turn()
+            .min_by_key(|&index| active[index].load(Ordering::Relaxed))
+    }
+
+    // Only the IP address is hashed, so that all connections from the
+    // same host go to the same destination. If that destination is
+    // not available, the next available one on the ring is picked, so
+    // the other sources keep their destinations.
+    fn consistent_hash(&self, source: SocketAddr) -> Option<usize> {
+        let key = hash(&source.ip());
+        let pos = self.ring.partition_point(|&(point, _)| point < key);
+        (0..self.ring.len())
+            .map(|offset| self.ring[(pos + offset) % self.ring.len()].1)
+            .find(|&index| self.available[index])
+    }
+
+    fn weighted(&mut self) -> Option<usize> {
+        let weights: Vec<u64> = self
+            .weights
+            .iter()
+            .zip(&self.available)
+            .map(|(&weight, &available)| if available { u64::from(weight) } else { 0 })
+            .collect();
+        let total: u64 = weights.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = self.random.below(total);
+        for (index, &weight) in weights.iter().enumerate() {
+            if pick < weight {
+                return Some(index);
+            }
+            pick -= weight;
+        }
+        unreachable!("pick is below the total weight")
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_weights() {
+        assert_eq!("1, 2,0".parse(), Ok(Weights(vec![1, 2, 0])));
+        assert_eq!("1000".parse(), Ok(Weights(vec![MAX_WEIGHT])));
+        assert!("0,0".parse::<Weights>().is_err());
+        assert!("1001".parse::<Weights>().is_err());
+        assert!("50000000".parse::<Weights>().is_err());
+        assert!("1,x".parse::<Weights>().is_err());
+    }
+
+    #[test]
+    fn configure_routing() {
+        let router = Router::configure(Mode::Tee, None, 2, None, None).unwrap();
+        assert_eq!(router.routing, Routing::Broadcast);
+        let router = Router::configure(Mode::Proxy, None, 2, None, None).unwrap();
+        assert_eq!(router.routing, Routing::RoundRobin);
+        let router = Router::configure(Mode::Proxy, Some(Routing::Weighted), 2, None, None);
+        assert_eq!(router.unwrap().routing, Routing::Weighted);
+    }
+
+    #[test]
+    fn configure_errors() {
+        let broadcast = Some(Routing::Broadcast);
+        assert!(Router::configure(Mode::Proxy, broadcast, 2, None, None).is_err());
+        assert!(Router::configure(Mode::Tee, None, 0, None, None).is_err());
+        let weights = Weights(vec![1, 2, 3]);
+        assert!(Router::configure(Mode::Tee, None, 2, Some(&weights), None).is_err());
+    }
+
+    #[test]
+    fn weights_are_followed() {
+        let weights = Weights(vec![0, MAX_WEIGHT]);
+        let mut router = Router::configure(
+            Mode::Tee,
+            Some(Routing::Weighted),
+            2,
+            Some(&weights),
+            Some(1),
+        )
+        .unwrap();
+        let source = "127.0.0.1:1".parse().unwrap();
+        for _ in 0..100 {
+            assert_eq!(pick(&mut router, source), Some(1));
+        }
+    }
+
+    // Pick a destination and return its index.
+    fn pick(router: &mut Router, source: SocketAddr) -> Option<usize> {
+        match router.route(source)? {
+            Route::One(lease) => Some(lease.index()),
+            Route::All => panic!("expected a single destination"),
+        }
+    }
+
+    fn source(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn round_robin_order() {
+        let mut router = Router::new(Routing::RoundRobin, 3);
+        let picks: Vec<_> = (0..5).map(|_| pick(&mut router, source(1))).collect();
+        assert_eq!(picks, vec![Some(0), Some(1), Some(2), Some(0), Some(1)]);
+
+        // Unavailable destinations are skipped without changing the
+        // order of the others.
+        router.set_available(2, false);
+        let picks: Vec<_> = (0..3).map(|_| pick(&mut router, source(1))).collect();
+        assert_eq!(picks, vec![Some(0), Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn least_connections_leases() {
+        let mut router = Router::new(Routing::LeastConnections, 3);
+        let first = router.route(source(1)).unwrap();
+        let second = router.route(source(2)).unwrap();
+        let third = router.route(source(3)).unwrap();
+        assert_eq!(router.active(), vec![1, 1, 1]);
+
+        // Releasing a lease makes its destination the least loaded.
+        drop(second);
+        assert_eq!(router.active(), vec![1, 0, 1]);
+        assert_eq!(pick(&mut router, source(4)), Some(1));
+        assert_eq!(router.active(), vec![1, 0, 1]);
+
+        let fourth = router.route(source(4)).unwrap();
+        assert_eq!(router.active(), vec![1, 1, 1]);
+        drop((first, third, fourth));
+        assert_eq!(router.active(), vec![0, 0, 0]);
+
+        // The least loaded destination is skipped if unavailable.
+        let _lease = router.route(source(1)).unwrap();
+        let busy: Vec<_> = router.active();
+        let idle = busy.iter().position(|&count| count == 0).unwrap();
+        router.set_available(idle, false);
+        let index = pick(&mut router, source(2)).unwrap();
+        assert_ne!(index, idle);
+    }
+
+    #[test]
+    fn consistent_hash_is_stable() {
+        let mut router = Router::new(Routing::ConsistentHash, 4);
+        let sources: Vec<SocketAddr> = (1..=50)
+            .map(|host| SocketAddr::from(([10, 0, 0, host], 1000)))
+            .collect();
+        let picks: Vec<_> = sources.iter().map(|&s| pick(&mut router, s)).collect();
+
+        // The same host goes to the same destination, whatever the
+        // port and how many times it is routed.
+        for (&source, &index) in sources.iter().zip(&picks) {
+            let other = SocketAddr::new(source.ip(), 2000);
+            assert_eq!(pick(&mut router, other), index);
+        }
+        let used: std::collections::HashSet<_> = picks.iter().collect();
+        assert_eq!(used.len(), 4);
+
+        // Only the sources of an unavailable destination move.
+        router.set_available(1, false);
+        for (&source, &index) in sources.iter().zip(&picks) {
+            let moved = pick(&mut router, source);
+            if index == Some(1) {
+                assert_ne!(moved, Some(1));
+            } else {
+                assert_eq!(moved, index);
+            }
+        }
+    }
+
+    #[test]
+    fn skip_unavailable() {
+        let weights = Weights(vec![1, 0, 1]);
+        let mut router = Router::configure(
+            Mode::Tee,
+            Some(Routing::Weighted),
+            3,
+            Some(&weights),
+            Some(1),
+        )
+        .unwrap();
+        router.set_available(0, false);
+        for _ in 0..20 {
+            assert_eq!(pick(&mut router, source(1)), Some(2));
+        }
+
+        // The destination with weight zero is not picked even if it is
+        // the only one available.
+        router.set_available(2, false);
+        assert_eq!(pick(&mut router, source(1)), None);
+
+        let mut router = Router::new(Routing::Broadcast, 2);
+        router.set_available(0, false);
+        assert!(matches!(router.route(source(1)), Some(Route::All)));
+        router.set_available(1, false);
+        assert!(router.route(source(1)).is_none());
+    }
+}
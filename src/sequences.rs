@@ -0,0 +1,493 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Streams of integer sequences.
+//!
+//! All the streams in this module are built using `stream::unfold`
+//! where the state of the unfold is the state needed to compute the
+//! next number of the sequence. The streams are generic over the
+//! integer type, so you can pick the type that suits the range of
+//! numbers that you need.
+//!
+//! Note that the streams use plain arithmetic, so they will panic on
+//! overflow in debug builds and wrap around in release builds. For
+//! Fibonacci numbers there are also variants with defined behavior on
+//! overflow: `checked_fibonacci`, `try_fibonacci`,
+//! `wrapping_fibonacci`, and `big_fibonacci`.
+
+use futures::{stream, Stream, StreamExt};
+use num_bigint::BigUint;
+use num_traits::{CheckedAdd, CheckedMul, CheckedSub, Num, WrappingAdd, WrappingMul, WrappingSub};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Error produced when the next number of a sequence does not fit in
+/// the integer type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverflowError;
+
+impl fmt::Display for OverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sequence overflowed the integer type")
+    }
+}
+
+impl std::error::Error for OverflowError {}
+
+/// Generate a stream of Fibonacci numbers, starting with 1, 1.
+pub fn fibonacci<T>() -> impl Stream<Item = T>
+where
+    T: Num + Clone,
+{
+    stream::unfold((T::one(), T::one()), |(curr, next)| async move {
+        let after = curr.clone() + next.clone();
+        Some((curr, (next, after)))
+    })
+}
+
+/// Generate a stream of Fibonacci numbers that ends on overflow.
+///
+/// The stream produces all Fibonacci numbers that fit in the integer
+/// type and then ends, so for `u64` the last number is the 93rd
+/// Fibonacci number.
+pub fn checked_fibonacci<T>() -> impl Stream<Item = T>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    checked_fibonacci_from(1)
+}
+
+/// Generate a stream of Fibonacci numbers starting with the Fibonacci
+/// number with the given index.
+///
+/// The first number is computed using `nth_fibonacci`, so it is not
+/// necessary to generate all the numbers before it. Same as for
+/// `checked_fibonacci`, the stream ends on overflow, which means that
+/// it is empty if the first number does not fit in the integer type.
+pub fn checked_fibonacci_from<T>(index: u64) -> impl Stream<Item = T>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    // The state holds the current number and the next number, if it
+    // could be computed without overflowing.
+    let start = nth_fibonacci(index).map(|curr: T| {
+        let next = index.checked_add(1).and_then(nth_fibonacci);
+        (curr, next)
+    });
+    stream::unfold(start, |state| async move {
+        let (curr, next) = state?;
+        let state = next.map(|next| {
+            let after = curr.checked_add(&next);
+            (next, after)
+        });
+        Some((curr, state))
+    })
+}
+
+/// Generate a stream of Fibonacci numbers that signals overflow.
+///
+/// This works like `checked_fibonacci` but instead of just ending the
+/// stream, it produces an `OverflowError` as the last item.
+pub fn try_fibonacci<T>() -> impl Stream<Item = Result<T, OverflowError>>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    try_fibonacci_from(1)
+}
+
+/// Generate a stream of Fibonacci numbers starting with the Fibonacci
+/// number with the given index and signal overflow.
+///
+/// This works like `checked_fibonacci_from` but produces an
+/// `OverflowError` as the last item.
+pub fn try_fibonacci_from<T>(index: u64) -> impl Stream<Item = Result<T, OverflowError>>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    checked_fibonacci_from(index)
+        .map(Ok)
+        .chain(stream::once(async { Err(OverflowError) }))
+}
+
+/// Compute the Fibonacci number with index `n`.
+///
+/// The numbers are indexed so that the 0th number is 0 and the 1st
+/// and 2nd numbers are 1. The number is computed in O(log n) steps
+/// using the fast-doubling identities:
+///
+/// - F(2k) = F(k) * (2 * F(k + 1) - F(k))
+/// - F(2k + 1) = F(k + 1)^2 + F(k)^2
+///
+/// Returns `None` if the number does not fit in the integer type.
+pub fn nth_fibonacci<T>(n: u64) -> Option<T>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    if n == 0 {
+        return Some(T::zero());
+    }
+    let (curr, next) = fibonacci_pair::<T>(n / 2)?;
+    if n.is_multiple_of(2) {
+        fibonacci_double(&curr, &next)
+    } else {
+        fibonacci_double_plus_one(&curr, &next)
+    }
+}
+
+// Compute F(n) and F(n + 1) using fast doubling.
+fn fibonacci_pair<T>(n: u64) -> Option<(T, T)>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    if n == 0 {
+        return Some((T::zero(), T::one()));
+    }
+    let (curr, next) = fibonacci_pair::<T>(n / 2)?;
+    let even = fibonacci_double(&curr, &next)?;
+    let odd = fibonacci_double_plus_one(&curr, &next)?;
+    if n.is_multiple_of(2) {
+        Some((even, odd))
+    } else {
+        let after = even.checked_add(&odd)?;
+        Some((odd, after))
+    }
+}
+
+// Compute F(2k) given F(k) and F(k + 1).
+//
+// The factor 2 * F(k + 1) - F(k) is computed as F(k + 1) + (F(k + 1)
+// - F(k)) so that it does not overflow unless F(2k) overflows.
+fn fibonacci_double<T>(curr: &T, next: &T) -> Option<T>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    let factor = next.checked_add(&next.checked_sub(curr)?)?;
+    curr.checked_mul(&factor)
+}
+
+// Compute F(2k + 1) given F(k) and F(k + 1).
+fn fibonacci_double_plus_one<T>(curr: &T, next: &T) -> Option<T>
+where
+    T: Num + Clone + CheckedAdd + CheckedSub + CheckedMul,
+{
+    curr.checked_mul(curr)?
+        .checked_add(&next.checked_mul(next)?)
+}
+
+/// Generate a stream of Fibonacci numbers that wraps around on
+/// overflow.
+///
+/// The numbers are the Fibonacci numbers modulo the size of the
+/// integer type, so the stream never ends.
+pub fn wrapping_fibonacci<T>() -> impl Stream<Item = T>
+where
+    T: Num + Clone + WrappingAdd + WrappingSub + WrappingMul,
+{
+    wrapping_fibonacci_from(1)
+}
+
+/// Generate a stream of Fibonacci numbers that wraps around on
+/// overflow, starting with the Fibonacci number with the given index.
+///
+/// The first number is computed using `wrapping_nth_fibonacci`, so it
+/// is not necessary to generate all the numbers before it.
+pub fn wrapping_fibonacci_from<T>(index: u64) -> impl Stream<Item = T>
+where
+    T: Num + Clone + WrappingAdd + WrappingSub + WrappingMul,
+{
+    let start = wrapping_fibonacci_pair::<T>(index);
+    stream::unfold(start, |(curr, next)| async move {
+        let after = curr.wrapping_add(&next);
+        Some((curr, (next, after)))
+    })
+}
+
+/// Compute the Fibonacci number with index `n` modulo the size of the
+/// integer type.
+///
+/// This uses the same fast-doubling identities as `nth_fibonacci`.
+/// They only use addition, subtraction, and multiplication, so they
+/// hold for wrapping arithmetic as well and the result is exact
+/// modulo the size of the integer type.
+pub fn wrapping_nth_fibonacci<T>(n: u64) -> T
+where
+    T: Num + Clone + WrappingAdd + WrappingSub + WrappingMul,
+{
+    wrapping_fibonacci_pair::<T>(n).0
+}
+
+// Compute F(n) and F(n + 1) using fast doubling with wrapping
+// arithmetic.
+fn wrapping_fibonacci_pair<T>(n: u64) -> (T, T)
+where
+    T: Num + Clone + WrappingAdd + WrappingSub + WrappingMul,
+{
+    if n == 0 {
+        return (T::zero(), T::one());
+    }
+    let (curr, next) = wrapping_fibonacci_pair::<T>(n / 2);
+    let factor = next.wrapping_add(&next.wrapping_sub(&curr));
+    let even = curr.wrapping_mul(&factor);
+    let odd = curr
+        .wrapping_mul(&curr)
+        .wrapping_add(&next.wrapping_mul(&next));
+    if n.is_multiple_of(2) {
+        (even, odd)
+    } else {
+        let after = even.wrapping_add(&odd);
+        (odd, after)
+    }
+}
+
+/// Generate a stream of arbitrary-precision Fibonacci numbers.
+///
+/// The stream never overflows but the numbers, and the time to
+/// compute them, will grow without bounds.
+pub fn big_fibonacci() -> impl Stream<Item = BigUint> {
+    fibonacci()
+}
+
+/// Generate a stream of Lucas numbers, starting with 2, 1.
+pub fn lucas<T>() -> impl Stream<Item = T>
+where
+    T: Num + Clone,
+{
+    let two = T::one() + T::one();
+    stream::unfold((two, T::one()), |(curr, next)| async move {
+        let after = curr.clone() + next.clone();
+        Some((curr, (next, after)))
+    })
+}
+
+/// Generate a stream of prime numbers.
+///
+/// Primes are found using trial division with the primes found so
+/// far, so the stream keeps all generated primes in memory.
+pub fn primes<T>() -> impl Stream<Item = T>
+where
+    T: Num + Clone + PartialOrd,
+{
+    let two = T::one() + T::one();
+    stream::unfold((two, Vec::new()), |(mut candidate, mut found)| async move {
+        while !is_prime(&candidate, &found) {
+            candidate = candidate + T::one();
+        }
+        found.push(candidate.clone());
+        let next = candidate.clone() + T::one();
+        Some((candidate, (next, found)))
+    })
+}
+
+// Check if a candidate is prime given all primes smaller than it.
+fn is_prime<T>(candidate: &T, primes: &[T]) -> bool
+where
+    T: Num + Clone + PartialOrd,
+{
+    primes
+        .iter()
+        .take_while(|&p| p.clone() * p.clone() <= *candidate)
+        .all(|p| !(candidate.clone() % p.clone()).is_zero())
+}
+
+/// Generate a stream of triangular numbers, starting with 1.
+pub fn triangular<T>() -> impl Stream<Item = T>
+where
+    T: Num + Clone,
+{
+    stream::unfold((T::one(), T::one()), |(index, sum)| async move {
+        let index = index + T::one();
+        let next = sum.clone() + index.clone();
+        Some((sum, (index, next)))
+    })
+}
+
+/// Generate a geometric sequence starting with `first` where each
+/// number is the previous one multiplied by `ratio`.
+pub fn geometric<T>(first: T, ratio: T) -> impl Stream<Item = T>
+where
+    T: Num + Clone,
+{
+    stream::unfold(first, move |curr| {
+        let ratio = ratio.clone();
+        async move {
+            let next = curr.clone() * ratio;
+            Some((curr, next))
+        }
+    })
+}
+
+/// Generate the Collatz sequence starting with `start`.
+///
+/// The stream ends after producing 1. If `start` is zero, the stream
+/// is empty.
+pub fn collatz<T>(start: T) -> impl Stream<Item = T>
+where
+    T: Num + Clone,
+{
+    let start = if start.is_zero() { None } else { Some(start) };
+    stream::unfold(start, |curr| async move {
+        let curr = curr?;
+        let next = if curr.is_one() {
+            None
+        } else {
+            let two = T::one() + T::one();
+            if (curr.clone() % two.clone()).is_zero() {
+                Some(curr.clone() / two)
+            } else {
+                Some(curr.clone() * (two + T::one()) + T::one())
+            }
+        };
+        Some((curr, next))
+    })
+}
+
+/// Generate a sequence defined by a linear recurrence.
+///
+/// The sequence starts with the numbers in `initial` and each
+/// following number is computed as the sum of the last
+/// `coefficients.len()` numbers, each multiplied with the
+/// corresponding coefficient. The coefficients are given with the
+/// coefficient of the oldest number first, so Fibonacci numbers are
+/// generated using `linear_recurrence(vec![1, 1], vec![1, 1])`.
+///
+/// # Panics
+///
+/// Panics if `initial` and `coefficients` have different lengths.
+pub fn linear_recurrence<T>(initial: Vec<T>, coefficients: Vec<T>) -> impl Stream<Item = T>
+where
+    T: Num + Clone,
+{
+    assert_eq!(
+        initial.len(),
+        coefficients.len(),
+        "need one initial value for each coefficient"
+    );
+    let window: VecDeque<T> = initial.into_iter().collect();
+    stream::unfold(window, move |mut window| {
+        let next = coefficients
+            .iter()
+            .zip(window.iter())
+            .fold(T::zero(), |sum, (c, x)| sum + c.clone() * x.clone());
+        async move {
+            let curr = window.pop_front()?;
+            window.push_back(next);
+            Some((curr, window))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    // Collect the first `count` items of a stream.
+    fn take<S: Stream>(stream: S, count: usize) -> Vec<S::Item> {
+        block_on(stream.take(count).collect())
+    }
+
+    // Fibonacci numbers computed by stepping from F(0).
+    fn stepped(count: usize) -> Vec<u128> {
+        let mut numbers = vec![0, 1];
+        while numbers.len() < count {
+            let n = numbers.len();
+            numbers.push(numbers[n - 2] + numbers[n - 1]);
+        }
+        numbers.truncate(count);
+        numbers
+    }
+
+    #[test]
+    fn test_nth_fibonacci_u64() {
+        assert_eq!(nth_fibonacci::<u64>(0), Some(0));
+        assert_eq!(nth_fibonacci::<u64>(1), Some(1));
+        assert_eq!(nth_fibonacci::<u64>(93), Some(12_200_160_415_121_876_738));
+        assert_eq!(nth_fibonacci::<u64>(94), None);
+    }
+
+    #[test]
+    fn test_nth_fibonacci_u128() {
+        assert_eq!(
+            nth_fibonacci::<u128>(186),
+            Some(332_825_110_087_067_562_321_196_029_789_634_457_848)
+        );
+        assert_eq!(nth_fibonacci::<u128>(187), None);
+    }
+
+    #[test]
+    fn test_nth_fibonacci_big() {
+        let expected: BigUint = "43466557686937456435688527675040625802564660517371780402481729089536555417949051890403879840079255169295922593080322634775209689623239873322471161642996440906533187938298969649928516003704476137795166849228875"
+            .parse()
+            .unwrap();
+        assert_eq!(nth_fibonacci::<BigUint>(1000), Some(expected.clone()));
+        assert_eq!(take(big_fibonacci().skip(999), 1), vec![expected]);
+    }
+
+    #[test]
+    fn test_nth_fibonacci_matches_stepping() {
+        for (n, &number) in stepped(187).iter().enumerate() {
+            assert_eq!(nth_fibonacci::<u128>(n as u64), Some(number));
+        }
+    }
+
+    #[test]
+    fn test_checked_fibonacci_from() {
+        let numbers = stepped(187);
+        for start in [0, 1, 2, 10, 50, 180] {
+            let expected: Vec<u128> = numbers[start..].iter().copied().take(10).collect();
+            assert_eq!(
+                take(checked_fibonacci_from::<u128>(start as u64), 10),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_checked_fibonacci_ends_on_overflow() {
+        let numbers = take(checked_fibonacci::<u64>(), 100);
+        assert_eq!(numbers.len(), 93);
+        assert_eq!(numbers.last(), Some(&12_200_160_415_121_876_738));
+        assert!(take(checked_fibonacci_from::<u64>(94), 1).is_empty());
+    }
+
+    #[test]
+    fn test_try_fibonacci_from() {
+        let items = take(try_fibonacci_from::<u64>(92), 10);
+        assert_eq!(
+            items,
+            vec![
+                Ok(7_540_113_804_746_346_429),
+                Ok(12_200_160_415_121_876_738),
+                Err(OverflowError)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wrapping_fibonacci_from() {
+        let stepped: Vec<u64> = take(wrapping_fibonacci_from::<u64>(0), 300);
+        for (n, &number) in stepped.iter().enumerate() {
+            assert_eq!(wrapping_nth_fibonacci::<u64>(n as u64), number);
+        }
+        assert_eq!(
+            take(wrapping_fibonacci_from::<u64>(250), 5),
+            stepped[250..255].to_vec()
+        );
+        assert_eq!(take(wrapping_fibonacci::<u64>(), 5), stepped[1..6].to_vec());
+        assert_eq!(
+            wrapping_nth_fibonacci::<u64>(93),
+            12_200_160_415_121_876_738
+        );
+    }
+}
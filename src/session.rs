@@ -0,0 +1,415 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Sessions for UDP proxies.
+//!
+//! A UDP proxy cannot tell which client a reply from a server is for
+//! if all clients share the same socket to the server. Instead, each
+//! client gets a session with its own socket connected to the server,
+//! and the datagrams received on that socket are sent back to the
+//! client from the socket the proxy listens on. `Sessions` keeps the
+//! sessions by client address and closes the sessions that have been
+//! idle for too long.
+//!
+//! Each session has a task receiving the replies from the server, and
+//! the datagrams in each direction are sent by tasks that send them
+//! when they are due. When they are due is decided by a `Scheduler`
+//! for each direction, which can also drop or duplicate datagrams. By
+//! default, each datagram is sent once without delay.
+
+use crate::datagram::DatagramBuffer;
+use crate::impairment::{Impairer, Scheduled};
+use crate::routing::Lease;
+use bytes::Bytes;
+use futures::future;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{delay_until, Instant};
+
+// Number of datagrams that can wait to be scheduled in each
+// direction.
+const QUEUE_SIZE: usize = 32;
+
+// Datagram to send to an address at a point in time.
+type Packet = (Instant, Bytes, SocketAddr);
+
+/// Decides when, if at all, datagrams are sent.
+pub trait Scheduler: Send + Sync {
+    /// Decide what to do with a datagram.
+    ///
+    /// Returns the copies of the datagram to send, each with the delay
+    /// after which it should be sent.
+    fn schedule(&self, data: &[u8]) -> Vec<Scheduled>;
+}
+
+/// Scheduler sending each datagram once without delay.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Immediate;
+
+impl Scheduler for Immediate {
+    fn schedule(&self, data: &[u8]) -> Vec<Scheduled> {
+        vec![Scheduled {
+            delay: Duration::from_millis(0),
+            data: Bytes::copy_from_slice(data),
+        }]
+    }
+}
+
+impl Scheduler for Mutex<Impairer> {
+    fn schedule(&self, data: &[u8]) -> Vec<Scheduled> {
+        self.lock().unwrap().impair(data)
+    }
+}
+
+async fn wait_until(at: Option<Instant>) {
+    match at {
+        Some(at) => delay_until(at).await,
+        None => future::pending().await,
+    }
+}
+
+// Send the datagrams in the queue when they are due. Datagrams that
+// are due at the same time are sent in the order they were queued.
+async fn delay_line(mut socket: SendHalf, mut queue: mpsc::Receiver<Packet>) {
+    let mut pending = BinaryHeap::new();
+    let mut count: u64 = 0;
+    loop {
+        let next = pending
+            .peek()
+            .map(|Reverse((at, _, _, _)): &Reverse<(Instant, u64, Bytes, SocketAddr)>| *at);
+        tokio::select! {
+            packet = queue.recv() => match packet {
+                Some((at, data, addr)) => {
+                    pending.push(Reverse((at, count, data, addr)));
+                    count += 1;
+                }
+                None => break,
+            },
+            _ = wait_until(next) => {
+                if let Some(Reverse((_, _, data, addr))) = pending.pop() {
+                    if let Err(err) = socket.send_to(&data, &addr).await {
+                        println!("Send to {} failed: {}", addr, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Schedule a datagram and queue the copies to send.
+async fn schedule(
+    scheduler: &dyn Scheduler,
+    data: &[u8],
+    addr: SocketAddr,
+    queue: &mut mpsc::Sender<Packet>,
+) {
+    let now = Instant::now();
+    for copy in scheduler.schedule(data) {
+        if queue
+            .send((now + copy.delay, copy.data, addr))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+// Receive replies from the server until the session is closed.
+// Since the socket is connected to the server, datagrams from other
+// addresses are not received.
+async fn receive_replies(
+    mut upstream: RecvHalf,
+    client: SocketAddr,
+    scheduler: Arc<dyn Scheduler>,
+    mut replies: mpsc::Sender<Packet>,
+    last_seen: Arc<Mutex<Instant>>,
+    mut closed: oneshot::Receiver<()>,
+    mut buf: DatagramBuffer,
+) {
+    loop {
+        tokio::select! {
+            result = upstream.recv(buf.recv_buf()) => match result {
+                Ok(bytes) => {
+                    *last_seen.lock().unwrap() = Instant::now();
+                    let datagram = buf.datagram(bytes);
+                    if datagram.truncated {
+                        println!("Session {}: dropped reply larger than {} bytes", client, buf.max_size());
+                        continue;
+                    }
+                    schedule(&*scheduler, datagram.data, client, &mut replies).await;
+                }
+                // This is usually an ICMP error for an earlier
+                // datagram, so the session is kept.
+                Err(err) => println!("Session {}: receive failed: {}", client, err),
+            },
+            _ = &mut closed => break,
+        }
+    }
+}
+
+// Session for a client, with a dedicated socket connected to the
+// server.
+struct Session {
+    server: SocketAddr,
+    upstream: mpsc::Sender<Packet>,
+    last_seen: Arc<Mutex<Instant>>,
+
+    // Dropping these stops the task receiving replies and releases
+    // the server.
+    _closed: oneshot::Sender<()>,
+    _lease: Option<Lease>,
+}
+
+impl Session {
+    fn is_idle(&self, timeout: Duration) -> bool {
+        self.last_seen.lock().unwrap().elapsed() >= timeout
+    }
+}
+
+/// Sessions of the clients of a UDP proxy.
+pub struct Sessions {
+    sessions: HashMap<SocketAddr, Session>,
+    reply_queue: mpsc::Sender<Packet>,
+    buf: DatagramBuffer,
+    timeout: Duration,
+    requests: Arc<dyn Scheduler>,
+    replies: Arc<dyn Scheduler>,
+}
+
+impl Sessions {
+    /// Create an empty session table sending replies to the clients
+    /// using `socket`, which should be the sending half of the socket
+    /// the proxy receives on. Replies larger than the maximum size of
+    /// `buf` are dropped.
+    ///
+    /// Sessions expire after 60 seconds without traffic by default.
+    ///
+    /// This has to be called from within a runtime, since it spawns
+    /// the task sending the replies.
+    pub fn new(socket: SendHalf, buf: DatagramBuffer) -> Self {
+        let (reply_queue, queue) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(delay_line(socket, queue));
+        Self {
+            sessions: HashMap::new(),
+            reply_queue,
+            buf,
+            timeout: Duration::from_secs(60),
+            requests: Arc::new(Immediate),
+            replies: Arc::new(Immediate),
+        }
+    }
+
+    /// Set the time without traffic in either direction after which a
+    /// session expires.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the scheduler for the datagrams from the clients to the
+    /// servers.
+    pub fn request_scheduler(mut self, scheduler: Arc<dyn Scheduler>) -> Self {
+        self.requests = scheduler;
+        self
+    }
+
+    /// Set the scheduler for the replies from the servers to the
+    /// clients.
+    pub fn reply_scheduler(mut self, scheduler: Arc<dyn Scheduler>) -> Self {
+        self.replies = scheduler;
+        self
+    }
+
+    /// Number of open sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Check if there are no open sessions.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Check if a client has a session.
+    pub fn contains(&self, client: SocketAddr) -> bool {
+        self.sessions.contains_key(&client)
+    }
+
+    /// Server of the session of a client.
+    pub fn server(&self, client: SocketAddr) -> Option<SocketAddr> {
+        self.sessions.get(&client).map(|session| session.server)
+    }
+
+    /// Open a session for a client, connected to `server`. Any
+    /// existing session for the client is closed.
+    ///
+    /// The lease, if given, is kept until the session is closed.
+    pub async fn open(
+        &mut self,
+        client: SocketAddr,
+        server: SocketAddr,
+        lease: Option<Lease>,
+    ) -> io::Result<()> {
+        let any: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(any).await?;
+        socket.connect(server).await?;
+        let (upstream_recv, upstream_send) = socket.split();
+        let (upstream, queue) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(delay_line(upstream_send, queue));
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        let (closed, on_closed) = oneshot::channel();
+        tokio::spawn(receive_replies(
+            upstream_recv,
+            client,
+            self.replies.clone(),
+            self.reply_queue.clone(),
+            last_seen.clone(),
+            on_closed,
+            self.buf.clone(),
+        ));
+        let session = Session {
+            server,
+            upstream,
+            last_seen,
+            _closed: closed,
+            _lease: lease,
+        };
+        self.sessions.insert(client, session);
+        Ok(())
+    }
+
+    /// Send a datagram from a client to the server of its session.
+    ///
+    /// Returns false if the client has no session.
+    pub async fn send(&mut self, client: SocketAddr, data: &[u8]) -> bool {
+        let session = match self.sessions.get_mut(&client) {
+            Some(session) => session,
+            None => return false,
+        };
+        *session.last_seen.lock().unwrap() = Instant::now();
+        schedule(&*self.requests, data, session.server, &mut session.upstream).await;
+        true
+    }
+
+    /// Close the sessions that have expired and return the addresses
+    /// of their clients.
+    pub fn expire(&mut self) -> Vec<SocketAddr> {
+        let timeout = self.timeout;
+        let expired: Vec<SocketAddr> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_idle(timeout))
+            .map(|(&client, _)| client)
+            .collect();
+        for client in &expired {
+            self.sessions.remove(client);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::impairment::{Impairment, Probability};
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    // Echo all datagrams back to the sender.
+    async fn echo(mut socket: UdpSocket) {
+        let mut buf = [0; 64];
+        while let Ok((bytes, addr)) = socket.recv_from(&mut buf).await {
+            socket.send_to(&buf[..bytes], &addr).await.unwrap();
+        }
+    }
+
+    async fn recv(socket: &mut UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = [0; 64];
+        let received = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf));
+        let (bytes, addr) = received.await.expect("no reply received").unwrap();
+        (buf[..bytes].to_vec(), addr)
+    }
+
+    #[tokio::test]
+    async fn forward_replies_to_client() {
+        let server = bind().await;
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(echo(server));
+        let proxy = bind().await;
+        let proxy_addr = proxy.local_addr().unwrap();
+        let (_proxy_recv, proxy_send) = proxy.split();
+        let mut sessions = Sessions::new(proxy_send, DatagramBuffer::new(64).unwrap());
+        let mut client = bind().await;
+        let client_addr = client.local_addr().unwrap();
+
+        assert!(!sessions.send(client_addr, b"hello").await);
+        sessions.open(client_addr, server_addr, None).await.unwrap();
+        assert!(sessions.contains(client_addr));
+        assert_eq!(sessions.server(client_addr), Some(server_addr));
+        assert!(sessions.send(client_addr, b"hello").await);
+        assert_eq!(recv(&mut client).await, (b"hello".to_vec(), proxy_addr));
+    }
+
+    #[tokio::test]
+    async fn schedule_both_directions() {
+        let server = bind().await;
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(echo(server));
+        let (_proxy_recv, proxy_send) = bind().await.split();
+        let twice = Impairment::new().duplicate(Probability::new(1.0));
+        let requests = Arc::new(Mutex::new(Impairer::new(twice.clone(), 1)));
+        let replies = Arc::new(Mutex::new(Impairer::new(twice, 2)));
+        let mut sessions = Sessions::new(proxy_send, DatagramBuffer::new(64).unwrap())
+            .request_scheduler(requests.clone())
+            .reply_scheduler(replies.clone());
+        let mut client = bind().await;
+        let client_addr = client.local_addr().unwrap();
+
+        sessions.open(client_addr, server_addr, None).await.unwrap();
+        sessions.send(client_addr, b"hello").await;
+        for _ in 0..4 {
+            assert_eq!(recv(&mut client).await.0, b"hello".to_vec());
+        }
+        assert_eq!(requests.lock().unwrap().stats().duplicated, 1);
+        assert_eq!(replies.lock().unwrap().stats().duplicated, 2);
+    }
+
+    #[tokio::test]
+    async fn expire_idle_sessions() {
+        let (_proxy_recv, proxy_send) = bind().await.split();
+        let mut sessions = Sessions::new(proxy_send, DatagramBuffer::new(64).unwrap())
+            .timeout(Duration::from_millis(20));
+        let client = "127.0.0.1:1".parse().unwrap();
+        let server = "127.0.0.1:2".parse().unwrap();
+        sessions.open(client, server, None).await.unwrap();
+        assert!(sessions.expire().is_empty());
+        assert_eq!(sessions.len(), 1);
+        tokio::time::delay_for(Duration::from_millis(30)).await;
+        assert_eq!(sessions.expire(), vec![client]);
+        assert!(sessions.is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Stream that cycles over a shared array.
+//!
+//! The stream produces the items in the array in cyclic order. When
+//! the array is empty, the stream returns `Poll::Pending` and saves
+//! the waker of the task so that it can be woken when an item is
+//! pushed to the array. This is the difference from just asking to be
+//! woken immediately, which would make the task spin while the array
+//! is empty.
+
+use futures::stream::Stream;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+#[derive(Debug)]
+struct State<T> {
+    array: Vec<T>,
+    index: usize,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+impl<T> State<T> {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Stream that produces the items of a shared array in cyclic order.
+///
+/// Clones of the stream share the same array, so one clone can be
+/// used to push items to the array while another clone is used as a
+/// stream. Only the task that polled the stream last is woken when
+/// items are pushed, so only one clone should be used as a stream.
+#[derive(Debug)]
+pub struct SharedCycleStream<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Clone for SharedCycleStream<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Default for SharedCycleStream<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SharedCycleStream<T> {
+    /// Create a new stream with an empty array.
+    pub fn new() -> Self {
+        let state = State {
+            array: Vec::new(),
+            index: 0,
+            waker: None,
+            closed: false,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Push an item to the end of the array and wake the stream.
+    pub fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        state.array.push(item);
+        state.wake();
+    }
+
+    /// Remove all items from the array.
+    ///
+    /// The stream will not produce anything until new items are
+    /// pushed to the array.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.array.clear();
+        state.index = 0;
+    }
+
+    /// Close the stream and wake it.
+    ///
+    /// The stream ends the next time it is polled, even if there are
+    /// items in the array.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.wake();
+    }
+
+    /// Number of items in the array.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().array.len()
+    }
+
+    /// Check if the array is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone> Stream for SharedCycleStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            Poll::Ready(None)
+        } else if state.array.is_empty() {
+            // Save the waker so that we are woken when something is
+            // pushed to the array or the stream is closed.
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            if state.index >= state.array.len() {
+                state.index = 0;
+            }
+            let item = state.array[state.index].clone();
+            state.index += 1;
+            Poll::Ready(Some(item))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::stream::StreamExt;
+    use futures::task::{self, ArcWake};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Waker counting the number of times it was woken.
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl ArcWake for Counter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Counter {
+        fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    // Poll the stream once with a waker that counts the wakeups.
+    fn poll(stream: &mut SharedCycleStream<i32>, counter: &Arc<Counter>) -> Poll<Option<i32>> {
+        let waker = task::waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        stream.poll_next_unpin(&mut cx)
+    }
+
+    #[test]
+    fn cycle_over_items() {
+        let stream = SharedCycleStream::new();
+        for i in 1..=3 {
+            stream.push(i);
+        }
+        let items: Vec<i32> = block_on(stream.clone().take(7).collect());
+        assert_eq!(items, vec![1, 2, 3, 1, 2, 3, 1]);
+        assert_eq!(stream.len(), 3);
+    }
+
+    #[test]
+    fn wake_on_push() {
+        let mut stream = SharedCycleStream::new();
+        let counter = Arc::new(Counter::default());
+        assert_eq!(poll(&mut stream, &counter), Poll::Pending);
+        assert_eq!(counter.count(), 0);
+
+        stream.clone().push(1);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(poll(&mut stream, &counter), Poll::Ready(Some(1)));
+    }
+
+    #[test]
+    fn wait_after_clear() {
+        let mut stream = SharedCycleStream::new();
+        let counter = Arc::new(Counter::default());
+        stream.push(1);
+        stream.push(2);
+        assert_eq!(poll(&mut stream, &counter), Poll::Ready(Some(1)));
+
+        // After clearing, the stream waits for new items instead of
+        // being woken immediately.
+        stream.clear();
+        assert!(stream.is_empty());
+        assert_eq!(poll(&mut stream, &counter), Poll::Pending);
+        assert_eq!(counter.count(), 0);
+
+        // The stream starts from the beginning of the new items.
+        stream.push(3);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(poll(&mut stream, &counter), Poll::Ready(Some(3)));
+    }
+
+    #[test]
+    fn wake_on_close() {
+        let mut stream = SharedCycleStream::new();
+        let counter = Arc::new(Counter::default());
+        assert_eq!(poll(&mut stream, &counter), Poll::Pending);
+
+        stream.clone().close();
+        assert_eq!(counter.count(), 1);
+        stream.push(1);
+        assert_eq!(poll(&mut stream, &counter), Poll::Ready(None));
+    }
+}
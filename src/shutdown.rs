@@ -0,0 +1,161 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Graceful shutdown of tasks.
+//!
+//! A `Shutdown` is shared between all tasks that should stop on
+//! shutdown. Any clone can trigger the shutdown and all clones are
+//! notified about it. The simplest way for a task to observe the
+//! shutdown is to stop its main stream when it is triggered:
+//!
+//! ```ignore
+//! let mut ticks = interval(period).take_until(shutdown.wait());
+//! while let Some(_instant) = ticks.next().await {
+//!     // Work here is always completed before the loop ends.
+//! }
+//! ```
+
+use futures::future::{BoxFuture, FutureExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Shared shutdown signal.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    trigger: Arc<watch::Sender<bool>>,
+    signal: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    /// Create a new shutdown signal that is not triggered.
+    pub fn new() -> Self {
+        let (trigger, signal) = watch::channel(false);
+        Self {
+            trigger: Arc::new(trigger),
+            signal,
+        }
+    }
+
+    /// Trigger the shutdown.
+    pub fn trigger(&self) {
+        // This cannot fail since we hold a receiver ourselves.
+        let _ = self.trigger.broadcast(true);
+    }
+
+    /// Check if the shutdown has been triggered.
+    pub fn is_triggered(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Wait for the shutdown to be triggered.
+    ///
+    /// The returned future does not borrow the shutdown signal, so it
+    /// can be moved into a spawned task, and it is boxed, so it can be
+    /// used with combinators that require `Unpin`.
+    pub fn wait(&self) -> BoxFuture<'static, ()> {
+        let mut signal = self.signal.clone();
+        async move {
+            while let Some(triggered) = signal.recv().await {
+                if triggered {
+                    break;
+                }
+            }
+        }
+        .boxed()
+    }
+
+    /// Trigger the shutdown when Ctrl-C is pressed.
+    ///
+    /// This spawns a task, so it has to be called from inside the
+    /// runtime.
+    pub fn trigger_on_ctrl_c(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => match result {
+                    Ok(()) => shutdown.trigger(),
+                    Err(err) => println!("Unable to listen for Ctrl-C: {}", err),
+                },
+                _ = shutdown.wait() => {}
+            }
+        });
+    }
+
+    /// Trigger the shutdown after `duration`.
+    ///
+    /// This is useful to run the examples with a deadline. It spawns
+    /// a task, so it has to be called from inside the runtime.
+    pub fn trigger_after(&self, duration: Duration) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(duration).await;
+            shutdown.trigger();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use tokio::time::{self, Instant};
+
+    #[tokio::test]
+    async fn trigger_and_wait() {
+        let shutdown = Shutdown::new();
+        let clone = shutdown.clone();
+        assert!(!clone.is_triggered());
+
+        let waiter = tokio::spawn(clone.wait());
+        shutdown.trigger();
+        time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("wait did not finish")
+            .unwrap();
+        assert!(clone.is_triggered());
+
+        // Waiting after the trigger finishes immediately.
+        time::timeout(Duration::from_secs(1), clone.wait())
+            .await
+            .expect("wait did not finish");
+    }
+
+    #[tokio::test]
+    async fn trigger_after_duration() {
+        time::pause();
+        let start = Instant::now();
+        let shutdown = Shutdown::new();
+        shutdown.trigger_after(Duration::from_millis(100));
+        time::advance(Duration::from_millis(50)).await;
+        assert!(!shutdown.is_triggered());
+        shutdown.wait().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn stop_stream() {
+        time::pause();
+        let shutdown = Shutdown::new();
+        shutdown.trigger_after(Duration::from_millis(35));
+        let ticks = time::interval(Duration::from_millis(10)).take_until(shutdown.wait());
+        assert_eq!(ticks.count().await, 4);
+    }
+}
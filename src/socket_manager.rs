@@ -0,0 +1,663 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Sharing a UDP socket between tasks.
+//!
+//! Since a `UdpSocket` cannot be cloned, a `SocketManager` splits the
+//! socket and spawns one task that writes to it and one task that
+//! reads from it. Datagrams to send are passed to the writer task over
+//! a channel by a `SocketHandle`, which can be cloned, so any number
+//! of tasks can send on the same socket. The result of each send is
+//! passed back to the sender over a oneshot channel.
+//!
+//! Received datagrams are available from the `Incoming` stream, which
+//! also reports errors when receiving. Errors caused by a single
+//! datagram, like a datagram that is too large, do not stop the
+//! reader task.
+//!
+//! The manager keeps a registry of the peers that have sent datagrams
+//! to the socket, together with the time each was last seen. Peers
+//! that have been silent for longer than the peer expiry are
+//! forgotten. Datagrams can be sent to the peers selected by a
+//! `Destination`: all of them, a specific one, or the one that was
+//! seen most recently.
+//!
+//! ```ignore
+//! let manager = SocketManager::spawn(socket, DatagramBuffer::new(1024)?)?;
+//! let (handle, mut incoming) = manager.split();
+//! while let Some(result) = incoming.next().await {
+//!     let (data, addr) = result?;
+//!     handle.send(data, addr).await?;
+//! }
+//! ```
+
+use crate::datagram::DatagramBuffer;
+use bytes::Bytes;
+use futures::future;
+use futures::Stream;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+// Number of datagrams that can wait in each direction.
+const QUEUE_SIZE: usize = 32;
+
+/// Error returned when a datagram could not be sent.
+#[derive(Debug)]
+pub enum SendError {
+    /// Sending on the socket failed.
+    Io(io::Error),
+
+    /// The writer task is gone.
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Io(error) => write!(f, "send failed: {}", error),
+            SendError::Closed => write!(f, "socket manager is closed"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::Io(error) => Some(error),
+            SendError::Closed => None,
+        }
+    }
+}
+
+/// Error reported when receiving from the socket.
+#[derive(Debug)]
+pub enum RecvError {
+    /// Datagram that was larger than the maximum datagram size.
+    Truncated { addr: SocketAddr, max_size: usize },
+
+    /// Receiving from the socket failed.
+    Io(io::Error),
+}
+
+impl RecvError {
+    /// Check if the error stops the reader task.
+    ///
+    /// Truncated datagrams are not fatal, and neither are connection
+    /// errors, which are ICMP errors for an earlier datagram and do
+    /// not affect the socket.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            RecvError::Truncated { .. } => false,
+            RecvError::Io(error) => !matches!(
+                error.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+            ),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Truncated { addr, max_size } => write!(
+                f,
+                "datagram from {} is larger than {} bytes",
+                addr, max_size
+            ),
+            RecvError::Io(error) => write!(f, "receive failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RecvError::Truncated { .. } => None,
+            RecvError::Io(error) => Some(error),
+        }
+    }
+}
+
+/// Selection of the peers to send to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// All peers.
+    All,
+
+    /// The peer with this address, if it is known.
+    Peer(SocketAddr),
+
+    /// The peer that was seen most recently.
+    MostRecent,
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Destination::All),
+            "recent" => Ok(Destination::MostRecent),
+            _ => s
+                .parse()
+                .map(Destination::Peer)
+                .map_err(|_| format!("unknown destination '{}'", s)),
+        }
+    }
+}
+
+/// Peer that has sent datagrams to the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+}
+
+/// Registry of the peers that have sent datagrams to a socket.
+///
+/// The registry is not bounded in size. Expired peers are ignored,
+/// but they are only removed by `expire`, so it has to be called
+/// regularly to keep the registry from growing with every new peer.
+#[derive(Debug, Clone, Default)]
+pub struct Peers {
+    expiry: Option<Duration>,
+    peers: HashMap<SocketAddr, Instant>,
+}
+
+impl Peers {
+    /// Create an empty registry where peers never expire.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the time after which a silent peer is forgotten.
+    pub fn expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+
+    fn is_live(&self, last_seen: Instant) -> bool {
+        self.expiry
+            .is_none_or(|expiry| last_seen.elapsed() < expiry)
+    }
+
+    /// Record that a datagram was received from a peer now.
+    pub fn seen(&mut self, addr: SocketAddr) {
+        self.peers.insert(addr, Instant::now());
+    }
+
+    /// Remove the peers that have expired and return their addresses.
+    pub fn expire(&mut self) -> Vec<SocketAddr> {
+        let expired: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, &last_seen)| !self.is_live(last_seen))
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in &expired {
+            self.peers.remove(addr);
+        }
+        expired
+    }
+
+    /// Get a peer that has not expired.
+    pub fn get(&self, addr: SocketAddr) -> Option<Peer> {
+        match self.peers.get(&addr) {
+            Some(&last_seen) if self.is_live(last_seen) => Some(Peer { addr, last_seen }),
+            _ => None,
+        }
+    }
+
+    /// Get the peers that have not expired, the most recently seen
+    /// first.
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self
+            .peers
+            .iter()
+            .filter(|(_, &last_seen)| self.is_live(last_seen))
+            .map(|(&addr, &last_seen)| Peer { addr, last_seen })
+            .collect();
+        peers.sort_by_key(|peer| Reverse(peer.last_seen));
+        peers
+    }
+
+    /// Get the addresses of the peers selected by `dest`.
+    pub fn select(&self, dest: Destination) -> Vec<SocketAddr> {
+        match dest {
+            Destination::All => self.peers().iter().map(|peer| peer.addr).collect(),
+            Destination::Peer(addr) => self.get(addr).map(|peer| peer.addr).into_iter().collect(),
+            Destination::MostRecent => self
+                .peers()
+                .first()
+                .map(|peer| peer.addr)
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+// Request to the writer task.
+struct Request {
+    data: Bytes,
+    dest: SocketAddr,
+    reply: oneshot::Sender<io::Result<usize>>,
+}
+
+/// Handle for sending datagrams on a managed socket.
+#[derive(Debug, Clone)]
+pub struct SocketHandle {
+    sender: mpsc::Sender<Request>,
+    peers: Arc<Mutex<Peers>>,
+}
+
+impl SocketHandle {
+    /// Send a datagram to `dest` and return the number of bytes sent
+    /// once the writer task has sent it.
+    pub async fn send(&self, data: Bytes, dest: SocketAddr) -> Result<usize, SendError> {
+        let (reply, result) = oneshot::channel();
+        let request = Request { data, dest, reply };
+        self.sender
+            .clone()
+            .send(request)
+            .await
+            .map_err(|_| SendError::Closed)?;
+        match result.await {
+            Ok(result) => result.map_err(SendError::Io),
+            Err(_) => Err(SendError::Closed),
+        }
+    }
+
+    /// Send a datagram to the peers selected by `dest`.
+    ///
+    /// The datagram is sent to all the peers concurrently, and the
+    /// result for each peer is returned.
+    pub async fn send_to_peers(
+        &self,
+        data: Bytes,
+        dest: Destination,
+    ) -> Vec<(SocketAddr, Result<usize, SendError>)> {
+        let addrs = self.select(dest);
+        future::join_all(addrs.into_iter().map(|addr| {
+            let data = data.clone();
+            async move { (addr, self.send(data, addr).await) }
+        }))
+        .await
+    }
+
+    /// Get the addresses of the peers selected by `dest`.
+    pub fn select(&self, dest: Destination) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().select(dest)
+    }
+
+    /// Get the peers that have not expired, the most recently seen
+    /// first.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.lock().unwrap().peers()
+    }
+
+    /// Forget the peers that have expired and return their addresses.
+    pub fn expire_peers(&self) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().expire()
+    }
+}
+
+/// Stream of the datagrams received on a managed socket.
+///
+/// The stream ends after a fatal error. If it is dropped, the reader
+/// task stops when the next datagram is received.
+#[derive(Debug)]
+pub struct Incoming {
+    receiver: mpsc::Receiver<Result<(Bytes, SocketAddr), RecvError>>,
+}
+
+impl Incoming {
+    /// Receive the next datagram and the address it came from.
+    pub async fn recv(&mut self) -> Option<Result<(Bytes, SocketAddr), RecvError>> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for Incoming {
+    type Item = Result<(Bytes, SocketAddr), RecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// UDP socket shared between tasks.
+#[derive(Debug)]
+pub struct SocketManager {
+    local_addr: SocketAddr,
+    handle: SocketHandle,
+    incoming: Incoming,
+}
+
+impl SocketManager {
+    /// Take over the socket and spawn the tasks reading and writing
+    /// it. Datagrams are received into `buf`, which decides the
+    /// maximum datagram size.
+    ///
+    /// Peers never expire unless an expiry is set using
+    /// `peer_expiry`, so without it the registry grows with every new
+    /// peer address for as long as the manager runs. With an expiry,
+    /// `SocketHandle::expire_peers` has to be called regularly to
+    /// remove the expired peers.
+    pub fn spawn(socket: UdpSocket, buf: DatagramBuffer) -> io::Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let (reader, writer) = socket.split();
+        let (sender, requests) = mpsc::channel(QUEUE_SIZE);
+        let (datagrams, receiver) = mpsc::channel(QUEUE_SIZE);
+        let peers = Arc::new(Mutex::new(Peers::new()));
+        tokio::spawn(write(writer, requests));
+        tokio::spawn(read(reader, buf, datagrams, peers.clone()));
+        Ok(Self {
+            local_addr,
+            handle: SocketHandle { sender, peers },
+            incoming: Incoming { receiver },
+        })
+    }
+
+    /// Set the time after which a silent peer is forgotten.
+    pub fn peer_expiry(self, expiry: Duration) -> Self {
+        self.handle.peers.lock().unwrap().expiry = Some(expiry);
+        self
+    }
+
+    /// Address the socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Get a handle for sending on the socket.
+    pub fn handle(&self) -> SocketHandle {
+        self.handle.clone()
+    }
+
+    /// Receive the next datagram and the address it came from.
+    pub async fn recv(&mut self) -> Option<Result<(Bytes, SocketAddr), RecvError>> {
+        self.incoming.recv().await
+    }
+
+    /// Split the manager into a handle for sending and the stream of
+    /// received datagrams.
+    pub fn split(self) -> (SocketHandle, Incoming) {
+        (self.handle, self.incoming)
+    }
+}
+
+// Send the requested datagrams until all handles are dropped.
+async fn write(mut writer: SendHalf, mut requests: mpsc::Receiver<Request>) {
+    while let Some(Request { data, dest, reply }) = requests.recv().await {
+        let result = writer.send_to(&data, &dest).await;
+        // The sender is not interested in the result if it is gone.
+        let _ = reply.send(result);
+    }
+}
+
+// Receive datagrams until a fatal error or until the stream is
+// dropped, and record the peers they come from.
+async fn read(
+    mut reader: RecvHalf,
+    mut buf: DatagramBuffer,
+    mut datagrams: mpsc::Sender<Result<(Bytes, SocketAddr), RecvError>>,
+    peers: Arc<Mutex<Peers>>,
+) {
+    loop {
+        let result = match reader.recv_from(buf.recv_buf()).await {
+            Ok((bytes, addr)) => {
+                peers.lock().unwrap().seen(addr);
+                let datagram = buf.datagram(bytes);
+                if datagram.truncated {
+                    Err(RecvError::Truncated {
+                        addr,
+                        max_size: buf.max_size(),
+                    })
+                } else {
+                    Ok((Bytes::copy_from_slice(datagram.data), addr))
+                }
+            }
+            Err(error) => Err(RecvError::Io(error)),
+        };
+        let fatal = result.as_ref().map_or_else(RecvError::is_fatal, |_| false);
+        if datagrams.send(result).await.is_err() || fatal {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::time::timeout;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    async fn spawn(max_size: usize) -> SocketManager {
+        SocketManager::spawn(bind().await, DatagramBuffer::new(max_size).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn send_returns_byte_count() {
+        let manager = spawn(64).await;
+        let mut peer = bind().await;
+        let peer_addr = peer.local_addr().unwrap();
+        let bytes = manager
+            .handle()
+            .send(Bytes::from_static(b"hello"), peer_addr)
+            .await
+            .unwrap();
+        assert_eq!(bytes, 5);
+
+        let mut buf = [0; 64];
+        let (bytes, addr) = timeout(TIMEOUT, peer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..bytes], b"hello");
+        assert_eq!(addr, manager.local_addr());
+    }
+
+    #[tokio::test]
+    async fn fail_when_writer_is_gone() {
+        let dest = "127.0.0.1:1".parse().unwrap();
+
+        // The writer task is gone before the request is sent.
+        let (sender, requests) = mpsc::channel(1);
+        drop(requests);
+        let handle = SocketHandle {
+            sender,
+            peers: Arc::default(),
+        };
+        let result = handle.send(Bytes::from_static(b"hello"), dest).await;
+        assert!(matches!(result, Err(SendError::Closed)));
+
+        // The writer task is gone after taking the request, but before
+        // replying.
+        let (sender, mut requests) = mpsc::channel(1);
+        tokio::spawn(async move {
+            requests.recv().await;
+        });
+        let handle = SocketHandle {
+            sender,
+            peers: Arc::default(),
+        };
+        let result = handle.send(Bytes::from_static(b"hello"), dest).await;
+        assert!(matches!(result, Err(SendError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn report_truncated_datagrams() {
+        let manager = spawn(8).await;
+        let dest = manager.local_addr();
+        let (_, mut incoming) = manager.split();
+        let mut peer = bind().await;
+        let peer_addr = peer.local_addr().unwrap();
+        peer.send_to(b"more than eight bytes", &dest).await.unwrap();
+        peer.send_to(b"hello", &dest).await.unwrap();
+
+        match timeout(TIMEOUT, incoming.next()).await.unwrap() {
+            Some(Err(RecvError::Truncated { addr, max_size })) => {
+                assert_eq!(addr, peer_addr);
+                assert_eq!(max_size, 8);
+            }
+            result => panic!("expected a truncated datagram, got {:?}", result),
+        }
+        match timeout(TIMEOUT, incoming.next()).await.unwrap() {
+            Some(Ok((data, addr))) => {
+                assert_eq!(data, Bytes::from_static(b"hello"));
+                assert_eq!(addr, peer_addr);
+            }
+            result => panic!("expected a datagram, got {:?}", result),
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    // Record the peers in order, with a short pause in between so
+    // that each is seen at a different time.
+    fn seen(peers: &mut Peers, ports: &[u16]) {
+        for &port in ports {
+            std::thread::sleep(Duration::from_millis(2));
+            peers.seen(addr(port));
+        }
+    }
+
+    fn addrs(peers: &[Peer]) -> Vec<SocketAddr> {
+        peers.iter().map(|peer| peer.addr).collect()
+    }
+
+    #[test]
+    fn order_peers_by_most_recent() {
+        let mut peers = Peers::new();
+        seen(&mut peers, &[1, 2, 3, 1]);
+        assert_eq!(addrs(&peers.peers()), vec![addr(1), addr(3), addr(2)]);
+    }
+
+    #[test]
+    fn expire_peers() {
+        let mut peers = Peers::new().expiry(Duration::from_millis(200));
+        seen(&mut peers, &[1, 2]);
+        std::thread::sleep(Duration::from_millis(250));
+        seen(&mut peers, &[2, 3]);
+        assert_eq!(addrs(&peers.peers()), vec![addr(3), addr(2)]);
+        assert_eq!(peers.get(addr(1)), None);
+        assert_eq!(peers.expire(), vec![addr(1)]);
+        assert!(peers.expire().is_empty());
+
+        // Without an expiry, peers are kept however long ago they were
+        // seen.
+        let mut peers = Peers::new();
+        seen(&mut peers, &[1]);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(peers.expire().is_empty());
+        assert!(peers.get(addr(1)).is_some());
+    }
+
+    #[test]
+    fn select_peers() {
+        let mut peers = Peers::new().expiry(Duration::from_millis(200));
+        assert!(peers.select(Destination::All).is_empty());
+        assert!(peers.select(Destination::MostRecent).is_empty());
+
+        seen(&mut peers, &[1]);
+        std::thread::sleep(Duration::from_millis(250));
+        seen(&mut peers, &[2, 3]);
+        assert_eq!(peers.select(Destination::All), vec![addr(3), addr(2)]);
+        assert_eq!(peers.select(Destination::MostRecent), vec![addr(3)]);
+        assert_eq!(peers.select(Destination::Peer(addr(2))), vec![addr(2)]);
+
+        // Unknown and expired peers are not selected.
+        assert!(peers.select(Destination::Peer(addr(4))).is_empty());
+        assert!(peers.select(Destination::Peer(addr(1))).is_empty());
+    }
+
+    #[test]
+    fn parse_destination() {
+        assert_eq!("all".parse(), Ok(Destination::All));
+        assert_eq!("recent".parse(), Ok(Destination::MostRecent));
+        assert_eq!("127.0.0.1:1".parse(), Ok(Destination::Peer(addr(1))));
+        assert!("127.0.0.1".parse::<Destination>().is_err());
+        assert!("everyone".parse::<Destination>().is_err());
+    }
+
+    #[tokio::test]
+    async fn send_to_all_peers() {
+        let manager = spawn(64).await;
+        let dest = manager.local_addr();
+        let (handle, mut incoming) = manager.split();
+        let mut clients = vec![bind().await, bind().await];
+        for client in &mut clients {
+            client.send_to(b"hello", &dest).await.unwrap();
+            timeout(TIMEOUT, incoming.next()).await.unwrap();
+        }
+
+        let mut results = handle
+            .send_to_peers(Bytes::from_static(b"reply"), Destination::All)
+            .await;
+        results.sort_by_key(|(addr, _)| *addr);
+        let mut expected: Vec<SocketAddr> = clients
+            .iter()
+            .map(|client| client.local_addr().unwrap())
+            .collect();
+        expected.sort();
+        assert_eq!(
+            results.iter().map(|(addr, _)| *addr).collect::<Vec<_>>(),
+            expected
+        );
+        assert!(results.iter().all(|(_, result)| matches!(result, Ok(5))));
+
+        let mut buf = [0; 64];
+        for client in &mut clients {
+            let (bytes, addr) = timeout(TIMEOUT, client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..bytes], b"reply");
+            assert_eq!(addr, dest);
+        }
+    }
+
+    #[test]
+    fn classify_errors() {
+        let truncated = RecvError::Truncated {
+            addr: "127.0.0.1:1".parse().unwrap(),
+            max_size: 8,
+        };
+        assert!(!truncated.is_fatal());
+        let io_error = |kind| RecvError::Io(io::Error::new(kind, "test"));
+        assert!(!io_error(io::ErrorKind::ConnectionRefused).is_fatal());
+        assert!(!io_error(io::ErrorKind::ConnectionReset).is_fatal());
+        assert!(io_error(io::ErrorKind::PermissionDenied).is_fatal());
+        assert!(io_error(io::ErrorKind::Other).is_fatal());
+    }
+}
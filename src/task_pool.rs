@@ -0,0 +1,269 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Run futures with bounded concurrency.
+//!
+//! A `TaskPool` takes an iterator of futures and runs at most a fixed
+//! number of them at the same time. The results are produced as a
+//! stream tagged with the position of the future in the iterator, so
+//! that the caller can tell which futures did not resolve because
+//! they timed out or were cancelled.
+//!
+//! The futures are polled by the task that polls the stream, so they
+//! do not have to be `Send`, and no thread is blocked while waiting
+//! for them.
+
+use crate::shutdown::Shutdown;
+use futures::future::{self, Future};
+use futures::stream::{self, Stream, StreamExt};
+use std::fmt;
+use std::time::Duration;
+use tokio::time;
+
+/// Order of the results produced by the task pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultOrder {
+    /// Produce results in the order the futures complete.
+    Completion,
+
+    /// Produce results in the order the futures were submitted.
+    Submission,
+}
+
+/// Reason a future did not resolve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskError {
+    /// The future did not complete within the timeout.
+    TimedOut,
+
+    /// The pool was cancelled before the future completed.
+    Cancelled,
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::TimedOut => write!(f, "task timed out"),
+            TaskError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for TaskError {}
+
+/// Pool running futures with a concurrency limit.
+#[derive(Debug, Clone)]
+pub struct TaskPool {
+    limit: usize,
+    order: ResultOrder,
+    timeout: Option<Duration>,
+    cancel: Option<Shutdown>,
+}
+
+impl TaskPool {
+    /// Create a pool that runs at most `limit` futures at the same
+    /// time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn new(limit: usize) -> Self {
+        assert!(limit > 0, "task pool limit must be non-zero");
+        Self {
+            limit,
+            order: ResultOrder::Completion,
+            timeout: None,
+            cancel: None,
+        }
+    }
+
+    /// Set the order of the results. The default is to produce the
+    /// results in completion order.
+    pub fn order(mut self, order: ResultOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Set the maximum time each future is allowed to run.
+    ///
+    /// The time is counted from when the future is started, not from
+    /// when it was submitted.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Cancel all futures that have not completed when `shutdown` is
+    /// triggered.
+    pub fn cancel_on(mut self, shutdown: &Shutdown) -> Self {
+        self.cancel = Some(shutdown.clone());
+        self
+    }
+
+    /// Run the futures and return a stream of the results.
+    ///
+    /// Each result is tagged with the position of the future in
+    /// `tasks`. The stream produces one result for each future, even
+    /// for the futures that did not resolve.
+    pub fn run<I>(
+        self,
+        tasks: I,
+    ) -> impl Stream<Item = (usize, Result<<I::Item as Future>::Output, TaskError>)>
+    where
+        I: IntoIterator,
+        I::Item: Future,
+    {
+        let timeout = self.timeout;
+        let cancel = self.cancel;
+        let tasks = stream::iter(tasks.into_iter().enumerate()).map(move |(index, task)| {
+            let cancelled = cancel.as_ref().map(Shutdown::wait);
+            async move {
+                let result = run_task(task, timeout, cancelled).await;
+                (index, result)
+            }
+        });
+        match self.order {
+            ResultOrder::Completion => tasks.buffer_unordered(self.limit).left_stream(),
+            ResultOrder::Submission => tasks.buffered(self.limit).right_stream(),
+        }
+    }
+}
+
+// Run a single task with an optional timeout until it is cancelled.
+async fn run_task<F, C>(
+    task: F,
+    timeout: Option<Duration>,
+    cancelled: Option<C>,
+) -> Result<F::Output, TaskError>
+where
+    F: Future,
+    C: Future<Output = ()> + Unpin,
+{
+    let task = async move {
+        match timeout {
+            Some(duration) => time::timeout(duration, task)
+                .await
+                .map_err(|_| TaskError::TimedOut),
+            None => Ok(task.await),
+        }
+    };
+    futures::pin_mut!(task);
+    match cancelled {
+        Some(cancelled) => match future::select(task, cancelled).await {
+            future::Either::Left((result, _)) => result,
+            future::Either::Right(((), _)) => Err(TaskError::Cancelled),
+        },
+        None => task.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn millis(count: u64) -> Duration {
+        Duration::from_millis(count)
+    }
+
+    // Tasks that sleep for the given number of milliseconds and then
+    // return their duration.
+    fn sleepers(durations: &[u64]) -> Vec<impl Future<Output = u64>> {
+        durations
+            .iter()
+            .map(|&duration| async move {
+                time::delay_for(millis(duration)).await;
+                duration
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn limit_concurrency() {
+        time::pause();
+        let active = Rc::new(Cell::new(0));
+        let highest = Rc::new(Cell::new(0));
+        let tasks = (0..6).map(|_| {
+            let active = active.clone();
+            let highest = highest.clone();
+            async move {
+                active.set(active.get() + 1);
+                highest.set(highest.get().max(active.get()));
+                time::delay_for(millis(10)).await;
+                active.set(active.get() - 1);
+            }
+        });
+        let results: Vec<_> = TaskPool::new(2).run(tasks).collect().await;
+        assert_eq!(results.len(), 6);
+        assert_eq!(highest.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn order_results() {
+        time::pause();
+        let indexes = |results: Vec<(usize, Result<u64, TaskError>)>| -> Vec<usize> {
+            results.into_iter().map(|(index, _)| index).collect()
+        };
+
+        let pool = TaskPool::new(3);
+        let results = pool.run(sleepers(&[30, 10, 20])).collect().await;
+        assert_eq!(indexes(results), vec![1, 2, 0]);
+
+        let pool = TaskPool::new(3).order(ResultOrder::Submission);
+        let results = pool.run(sleepers(&[30, 10, 20])).collect().await;
+        assert_eq!(indexes(results), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn time_out_tasks() {
+        time::pause();
+        let pool = TaskPool::new(2).timeout(millis(20));
+        let results: Vec<_> = pool.run(sleepers(&[10, 50])).collect().await;
+        assert_eq!(results, vec![(0, Ok(10)), (1, Err(TaskError::TimedOut))]);
+
+        // The timeout starts when the task is started, so waiting in
+        // the queue does not count.
+        let pool = TaskPool::new(1).timeout(millis(20));
+        let results: Vec<_> = pool.run(sleepers(&[15, 15])).collect().await;
+        assert_eq!(results, vec![(0, Ok(15)), (1, Ok(15))]);
+    }
+
+    #[tokio::test]
+    async fn cancel_tasks() {
+        time::pause();
+        let shutdown = Shutdown::new();
+        let tasks = [10, 50, 50].iter().map(|&duration| {
+            let shutdown = shutdown.clone();
+            async move {
+                time::delay_for(millis(duration)).await;
+                // The first task to complete cancels the others.
+                shutdown.trigger();
+                duration
+            }
+        });
+        let pool = TaskPool::new(2)
+            .order(ResultOrder::Submission)
+            .cancel_on(&shutdown);
+        let results: Vec<_> = pool.run(tasks).collect().await;
+        assert_eq!(
+            results,
+            vec![
+                (0, Ok(10)),
+                (1, Err(TaskError::Cancelled)),
+                (2, Err(TaskError::Cancelled))
+            ]
+        );
+    }
+}
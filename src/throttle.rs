@@ -0,0 +1,407 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Stream combinator that limits the rate of a stream.
+//!
+//! A common way to pace a stream is to zip it with
+//! `tokio::time::interval`, but that gives little control over what
+//! happens when the stream cannot keep up with the interval: the
+//! interval will then produce all the missed ticks immediately, which
+//! makes the stream burst. The `throttle` combinator here supports a
+//! few different ways to limit the rate of a stream, and allows you to
+//! decide what should happen on missed ticks.
+
+use futures::stream::{Fuse, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{delay_until, Delay, Instant};
+
+/// How the rate of the stream is limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimit {
+    /// Produce at most one item each period.
+    FixedInterval(Duration),
+
+    /// Produce one item for each token in a bucket. The bucket holds
+    /// at most `burst` tokens and is refilled with one token each
+    /// period, so up to `burst` items can be produced immediately
+    /// after the stream has been idle.
+    TokenBucket { period: Duration, burst: u32 },
+
+    /// Read items into a bucket that holds at most `capacity` items
+    /// and produce one item from the bucket each period. Items that
+    /// arrive when the bucket is full are dropped.
+    ///
+    /// This is intended for streams where items arrive over time, such
+    /// as a channel or a socket. A stream that is always ready, such as
+    /// `stream::iter`, fills the bucket immediately, so most of its
+    /// items are dropped.
+    LeakyBucket { period: Duration, capacity: usize },
+}
+
+/// What to do when an item is produced after its tick.
+///
+/// This happens when the underlying stream was not ready at the time
+/// of the tick, or when the consumer did not poll the stream in time,
+/// and affects when the next tick will be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissedTickPolicy {
+    /// Keep the original schedule and produce items as fast as
+    /// possible until it has caught up. This is the behavior of
+    /// `tokio::time::interval` and the default.
+    #[default]
+    Burst,
+
+    /// Start a new schedule with the next tick one period after the
+    /// item was produced.
+    Delay,
+
+    /// Keep the original schedule but skip the missed ticks, so the
+    /// next tick is the first tick of the original schedule that is
+    /// in the future.
+    Skip,
+}
+
+/// Extension trait adding the `throttle` combinator to streams.
+pub trait ThrottleExt: Stream {
+    /// Create a stream that produces the items of this stream at a
+    /// rate limited by `limit`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the period is zero, or if the burst of a token bucket
+    /// or the capacity of a leaky bucket is zero.
+    fn throttle(self, limit: RateLimit) -> Throttle<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        Throttle::new(self, limit)
+    }
+}
+
+impl<S: Stream + ?Sized> ThrottleExt for S {}
+
+/// Stream for the `ThrottleExt::throttle` method.
+#[derive(Debug)]
+pub struct Throttle<S: Stream> {
+    stream: Fuse<S>,
+    limit: RateLimit,
+    policy: MissedTickPolicy,
+
+    // The delay until the next tick. It is created on the first poll,
+    // since a delay can only be created inside the runtime.
+    delay: Option<Delay>,
+
+    // Number of tokens in the bucket and the last time the bucket was
+    // refilled. Only used for the token bucket.
+    tokens: u32,
+    refilled: Option<Instant>,
+
+    // Items waiting in the bucket and the number of dropped items.
+    // Only used for the leaky bucket.
+    bucket: VecDeque<S::Item>,
+    dropped: u64,
+}
+
+// The bucket is only `Unpin` if the items are, but it is never
+// pinned, so the throttle is `Unpin` whenever the underlying stream is.
+impl<S: Stream + Unpin> Unpin for Throttle<S> {}
+
+impl<S: Stream + Unpin> Throttle<S> {
+    fn new(stream: S, limit: RateLimit) -> Self {
+        let (period, tokens) = match limit {
+            RateLimit::FixedInterval(period) => (period, 0),
+            RateLimit::TokenBucket { period, burst } => {
+                assert!(burst > 0, "token bucket cannot be empty");
+                (period, burst)
+            }
+            RateLimit::LeakyBucket { period, capacity } => {
+                assert!(capacity > 0, "leaky bucket cannot be empty");
+                (period, 0)
+            }
+        };
+        assert!(period > Duration::from_millis(0), "period must be non-zero");
+        Self {
+            stream: stream.fuse(),
+            limit,
+            policy: MissedTickPolicy::default(),
+            delay: None,
+            tokens,
+            refilled: None,
+            bucket: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Set the policy for missed ticks.
+    ///
+    /// This does not affect the token bucket, which instead limits
+    /// the burst by the size of the bucket.
+    pub fn missed_tick_policy(mut self, policy: MissedTickPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Number of items dropped because the leaky bucket was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Poll the delay until the next tick. If there is no delay, the
+    // tick is now.
+    fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.delay.as_mut() {
+            Some(delay) => Pin::new(delay).poll(cx),
+            None => Poll::Ready(()),
+        }
+    }
+
+    // Compute the next tick after an item was produced on the current
+    // tick.
+    fn schedule_next_tick(&mut self, period: Duration) {
+        let now = Instant::now();
+        let tick = self.delay.as_ref().map_or(now, |delay| delay.deadline());
+        let next = match self.policy {
+            MissedTickPolicy::Burst => tick + period,
+            MissedTickPolicy::Delay => now + period,
+            MissedTickPolicy::Skip => {
+                let mut next = tick + period;
+                while next <= now {
+                    next += period;
+                }
+                next
+            }
+        };
+        match self.delay.as_mut() {
+            Some(delay) => delay.reset(next),
+            None => self.delay = Some(delay_until(next)),
+        }
+    }
+
+    // Add the tokens for all the periods that have passed since the
+    // last refill, without overflowing the bucket.
+    fn refill(&mut self, period: Duration, burst: u32) {
+        let now = Instant::now();
+        let mut refilled = *self.refilled.get_or_insert(now);
+        while self.tokens < burst && refilled + period <= now {
+            refilled += period;
+            self.tokens += 1;
+        }
+        if self.tokens == burst {
+            refilled = now;
+        }
+        self.refilled = Some(refilled);
+    }
+
+    fn poll_fixed_interval(
+        &mut self,
+        cx: &mut Context<'_>,
+        period: Duration,
+    ) -> Poll<Option<S::Item>> {
+        futures::ready!(self.poll_tick(cx));
+        let item = futures::ready!(self.stream.poll_next_unpin(cx));
+        if item.is_some() {
+            self.schedule_next_tick(period);
+        }
+        Poll::Ready(item)
+    }
+
+    fn poll_token_bucket(
+        &mut self,
+        cx: &mut Context<'_>,
+        period: Duration,
+        burst: u32,
+    ) -> Poll<Option<S::Item>> {
+        self.refill(period, burst);
+        if self.tokens == 0 {
+            // The refill time is always set after a refill.
+            let next = self.refilled.unwrap() + period;
+            match self.delay.as_mut() {
+                Some(delay) => delay.reset(next),
+                None => self.delay = Some(delay_until(next)),
+            }
+            futures::ready!(self.poll_tick(cx));
+            self.refill(period, burst);
+        }
+        let item = futures::ready!(self.stream.poll_next_unpin(cx));
+        if item.is_some() {
+            self.tokens -= 1;
+        }
+        Poll::Ready(item)
+    }
+
+    fn poll_leaky_bucket(
+        &mut self,
+        cx: &mut Context<'_>,
+        period: Duration,
+        capacity: usize,
+    ) -> Poll<Option<S::Item>> {
+        // Read what is available from the stream into the bucket,
+        // dropping items that do not fit. We read at most `capacity`
+        // items each time, since the stream might always be ready. A
+        // stream that is always ready will then lose up to `capacity`
+        // items on each poll.
+        for _ in 0..capacity {
+            match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) if self.bucket.len() < capacity => {
+                    self.bucket.push_back(item)
+                }
+                Poll::Ready(Some(_)) => self.dropped += 1,
+                _ => break,
+            }
+        }
+
+        if self.bucket.is_empty() {
+            if self.stream.is_done() {
+                return Poll::Ready(None);
+            }
+            return Poll::Pending;
+        }
+
+        futures::ready!(self.poll_tick(cx));
+        self.schedule_next_tick(period);
+        Poll::Ready(self.bucket.pop_front())
+    }
+}
+
+impl<S: Stream + Unpin> Stream for Throttle<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.limit {
+            RateLimit::FixedInterval(period) => self.poll_fixed_interval(cx, period),
+            RateLimit::TokenBucket { period, burst } => self.poll_token_bucket(cx, period, burst),
+            RateLimit::LeakyBucket { period, capacity } => {
+                self.poll_leaky_bucket(cx, period, capacity)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use tokio::sync::mpsc;
+    use tokio::time::delay_for;
+
+    fn millis(count: u64) -> Duration {
+        Duration::from_millis(count)
+    }
+
+    // Read `count` items from the stream and record the time, in
+    // milliseconds since the start, at which each item arrived.
+    async fn timings<S: Stream + Unpin>(stream: &mut S, count: usize) -> Vec<u128> {
+        let start = Instant::now();
+        let mut result = Vec::new();
+        for _ in 0..count {
+            stream.next().await.unwrap();
+            result.push(start.elapsed().as_millis());
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn fixed_interval() {
+        tokio::time::pause();
+        let mut stream = stream::iter(1..=3).throttle(RateLimit::FixedInterval(millis(10)));
+        assert_eq!(timings(&mut stream, 3).await, vec![0, 10, 20]);
+        assert_eq!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn token_bucket() {
+        tokio::time::pause();
+        let limit = RateLimit::TokenBucket {
+            period: millis(10),
+            burst: 2,
+        };
+        let mut stream = stream::iter(1..).throttle(limit);
+        assert_eq!(timings(&mut stream, 4).await, vec![0, 0, 10, 20]);
+
+        // After being idle, the bucket is full again.
+        delay_for(millis(50)).await;
+        assert_eq!(timings(&mut stream, 3).await, vec![0, 0, 10]);
+    }
+
+    #[tokio::test]
+    async fn leaky_bucket() {
+        tokio::time::pause();
+        let limit = RateLimit::LeakyBucket {
+            period: millis(10),
+            capacity: 2,
+        };
+        let (mut sender, receiver) = mpsc::channel(10);
+        let mut stream = receiver.throttle(limit);
+        for i in 1..=4 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+
+        // The bucket holds two items, so the fourth item is dropped.
+        assert_eq!(timings(&mut stream, 3).await, vec![0, 10, 20]);
+        assert_eq!(stream.next().await, None);
+        assert_eq!(stream.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn leaky_bucket_with_ready_stream() {
+        tokio::time::pause();
+        let limit = RateLimit::LeakyBucket {
+            period: millis(10),
+            capacity: 3,
+        };
+        let mut stream = stream::iter(1..=10).throttle(limit);
+        let items: Vec<_> = (&mut stream).collect().await;
+        assert_eq!(items, vec![1, 2, 3, 4, 10]);
+        assert_eq!(stream.dropped(), 5);
+    }
+
+    // Produce one item, wait until the 35 ms mark, and produce three
+    // more items, recording the time of each item.
+    async fn late_consumer(policy: MissedTickPolicy) -> Vec<u128> {
+        let start = Instant::now();
+        let mut stream = stream::iter(1..)
+            .throttle(RateLimit::FixedInterval(millis(10)))
+            .missed_tick_policy(policy);
+        stream.next().await.unwrap();
+        delay_for(millis(35)).await;
+        let mut result = vec![0];
+        for _ in 0..3 {
+            stream.next().await.unwrap();
+            result.push(start.elapsed().as_millis());
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn missed_ticks() {
+        tokio::time::pause();
+        assert_eq!(
+            late_consumer(MissedTickPolicy::Burst).await,
+            vec![0, 35, 35, 35]
+        );
+        assert_eq!(
+            late_consumer(MissedTickPolicy::Delay).await,
+            vec![0, 35, 45, 55]
+        );
+        assert_eq!(
+            late_consumer(MissedTickPolicy::Skip).await,
+            vec![0, 35, 40, 50]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2019 Mats Kindahl
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you
+// may not use this file except in compliance with the License.  You
+// may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! Shared state that can be observed.
+//!
+//! A `VersionedCell` holds a value together with a version number
+//! that is incremented on each update. The value is protected by an
+//! asynchronous mutex, so tasks waiting for the value do not block
+//! the runtime thread, and the lock is never held by the caller, so it
+//! cannot be held across other await points by mistake.
+//!
+//! Tasks that want to react to changes can call `watch` to get a
+//! stream of all new versions of the value.
+
+use futures::stream::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{mpsc, Mutex};
+
+/// A value together with its version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<T> {
+    pub version: u64,
+    pub value: T,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    current: Versioned<T>,
+    watchers: Vec<mpsc::UnboundedSender<Versioned<T>>>,
+}
+
+/// Shared cell holding a versioned value.
+///
+/// Clones of the cell refer to the same value.
+#[derive(Debug)]
+pub struct VersionedCell<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Clone for VersionedCell<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Clone> VersionedCell<T> {
+    /// Create a new cell holding `value` with version 0.
+    pub fn new(value: T) -> Self {
+        let state = State {
+            current: Versioned { version: 0, value },
+            watchers: Vec::new(),
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Update the value using `f` and return the new version of the
+    /// value.
+    ///
+    /// All watchers are sent the new version of the value.
+    pub async fn update<F>(&self, f: F) -> Versioned<T>
+    where
+        F: FnOnce(&mut T),
+    {
+        let mut state = self.state.lock().await;
+        f(&mut state.current.value);
+        state.current.version += 1;
+        let current = state.current.clone();
+        state
+            .watchers
+            .retain(|watcher| watcher.send(current.clone()).is_ok());
+        current
+    }
+
+    /// Get a copy of the current version of the value.
+    pub async fn snapshot(&self) -> Versioned<T> {
+        self.state.lock().await.current.clone()
+    }
+
+    /// Create a stream of all new versions of the value.
+    ///
+    /// The stream produces every version after the current one, even
+    /// if the watcher is slower than the updates. The stream never
+    /// ends as long as there are clones of the cell.
+    pub async fn watch(&self) -> Watch<T> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.state.lock().await.watchers.push(sender);
+        Watch { receiver }
+    }
+}
+
+/// Stream for the `VersionedCell::watch` method.
+#[derive(Debug)]
+pub struct Watch<T> {
+    receiver: mpsc::UnboundedReceiver<Versioned<T>>,
+}
+
+impl<T> Stream for Watch<T> {
+    type Item = Versioned<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+
+    #[tokio::test]
+    async fn update_and_snapshot() {
+        let cell = VersionedCell::new(vec![1]);
+        assert_eq!(
+            cell.snapshot().await,
+            Versioned {
+                version: 0,
+                value: vec![1]
+            }
+        );
+
+        let updated = cell.clone().update(|value| value.push(2)).await;
+        assert_eq!(updated.version, 1);
+        assert_eq!(updated.value, vec![1, 2]);
+        assert_eq!(cell.snapshot().await, updated);
+    }
+
+    #[tokio::test]
+    async fn watch_all_versions() {
+        let cell = VersionedCell::new(0);
+        cell.update(|value| *value = 10).await;
+
+        // The watcher only sees the versions after the current one,
+        // and sees all of them even if it did not read them in time.
+        let mut first = cell.watch().await;
+        for i in 1..=3 {
+            cell.update(|value| *value += i).await;
+        }
+        let mut second = cell.watch().await;
+        cell.update(|value| *value += 4).await;
+
+        let versions: Vec<_> = (&mut first)
+            .take(4)
+            .map(|v| (v.version, v.value))
+            .collect()
+            .await;
+        assert_eq!(versions, vec![(2, 11), (3, 13), (4, 16), (5, 20)]);
+        let versions: Vec<_> = (&mut second)
+            .take(1)
+            .map(|v| (v.version, v.value))
+            .collect()
+            .await;
+        assert_eq!(versions, vec![(5, 20)]);
+    }
+
+    #[tokio::test]
+    async fn drop_watchers() {
+        let cell = VersionedCell::new(0);
+        let watch = cell.watch().await;
+        drop(watch);
+        cell.update(|value| *value += 1).await;
+        assert!(cell.state.lock().await.watchers.is_empty());
+    }
+}